@@ -1,9 +1,58 @@
+mod scram;
+
+pub use scram::{SCRAM_ITERATIONS, ScramCredentials};
+
 use argon2::{
     Argon2,
     password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
 };
-use rand::rngs::OsRng;
+use md5::{Digest, Md5};
+use rand::{RngCore, rngs::OsRng};
+use std::sync::OnceLock;
+use thiserror::Error;
+
+/// Tree holding the plaintext shared secrets used by APOP. APOP digests are
+/// computed over the secret itself, so these cannot be stored hashed.
+const APOP_TREE: &str = "apop_secrets";
+
+/// Tree holding each user's SCRAM-SHA-256 salt, iteration count and keys.
+const SCRAM_TREE: &str = "scram_sha256";
+
+/// Tree holding secrets the server generates for itself.
+const SECRETS_TREE: &str = "server_secrets";
+
+/// Key of the secret SCRAM salts for unknown users are derived from.
+const FAKE_SALT_SECRET: &str = "scram_fake_salt";
 
+/// Tree mapping a username to the upstream server a proxy sends it to.
+const ROUTES_TREE: &str = "proxy_routes";
+
+/// Returns an Argon2 hash of a random password. Logins for unknown users are
+/// checked against it so they take as long as logins for real ones.
+fn dummy_hash() -> &'static str {
+    static HASH: OnceLock<String> = OnceLock::new();
+    HASH.get_or_init(|| {
+        let mut password = [0u8; 32];
+        OsRng.fill_bytes(&mut password);
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(&password, &salt)
+            .unwrap()
+            .to_string()
+    })
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("database error: {0}")]
+    Db(#[from] sled::Error),
+    /// SASLprep (RFC 4013) refuses the password, for example because it
+    /// contains control characters.
+    #[error("password contains characters that are not allowed")]
+    InvalidPassword,
+}
+
+#[derive(Clone)]
 pub struct AuthStore {
     store: sled::Db,
 }
@@ -13,7 +62,7 @@ impl AuthStore {
         Self { store }
     }
 
-    pub fn create_user(&self, username: &str, password: &str) -> Result<bool, sled::Error> {
+    pub fn create_user(&self, username: &str, password: &str) -> Result<bool, AuthError> {
         println!("attempting to create new user {}", username);
         match self.store.get(username)? {
             Some(val) => {
@@ -24,28 +73,82 @@ impl AuthStore {
                 );
                 Ok(false)
             }
-            None => {
-                let salt = SaltString::generate(&mut OsRng);
-                let hasher = Argon2::default();
-                let hash = hasher
-                    .hash_password(password.as_bytes(), &salt)
-                    .unwrap()
-                    .to_string();
-                match self.store.insert(username, hash.into_bytes()) {
-                    Ok(_) => {
-                        println!("creating new user {}", username);
-                        Ok(true)
-                    }
-                    Err(e) => {
-                        println!("error creating new user {} {}", username, e);
-                        Err(e)
-                    }
+            None => match self.store_password(username, password) {
+                Ok(()) => {
+                    println!("creating new user {}", username);
+                    Ok(true)
                 }
-            }
+                Err(e) => {
+                    println!("error creating new user {} {}", username, e);
+                    Err(e)
+                }
+            },
+        }
+    }
+
+    /// Replaces the password of an existing user. Returns `false` if the user
+    /// does not exist.
+    pub fn change_password(&self, username: &str, password: &str) -> Result<bool, AuthError> {
+        if !self.store.contains_key(username)? {
+            return Ok(false);
         }
+        self.store_password(username, password)?;
+        Ok(true)
+    }
+
+    /// Writes the Argon2 hash and the SCRAM-SHA-256 credentials derived from
+    /// `password`. The password is normalized with SASLprep first, as it is
+    /// when a UTF8 session logs in, so both forms of a password match.
+    fn store_password(&self, username: &str, password: &str) -> Result<(), AuthError> {
+        let password = stringprep::saslprep(password).map_err(|_| AuthError::InvalidPassword)?;
+        let salt = SaltString::generate(&mut OsRng);
+        let hasher = Argon2::default();
+        let hash = hasher
+            .hash_password(password.as_bytes(), &salt)
+            .unwrap()
+            .to_string();
+        let scram = ScramCredentials::new(&password)?;
+        self.store
+            .open_tree(SCRAM_TREE)?
+            .insert(username, scram.to_bytes())?;
+        self.store.insert(username, hash.into_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the SCRAM-SHA-256 credentials for a user, if any are stored.
+    pub fn scram_credentials(
+        &self,
+        username: &str,
+    ) -> Result<Option<ScramCredentials>, sled::Error> {
+        let scram = self.store.open_tree(SCRAM_TREE)?;
+        Ok(scram
+            .get(username)?
+            .and_then(|bytes| ScramCredentials::from_bytes(&bytes)))
+    }
+
+    /// Returns credentials to run a SCRAM exchange against for a user that
+    /// does not exist, so that the server-first message does not reveal
+    /// which accounts do. The exchange then fails at client-final.
+    pub fn fake_scram_credentials(&self, username: &str) -> Result<ScramCredentials, sled::Error> {
+        let secrets = self.store.open_tree(SECRETS_TREE)?;
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        // Only the first secret written is kept, so every salt handed out
+        // stays the same across restarts.
+        let _ =
+            secrets.compare_and_swap(FAKE_SALT_SECRET, None as Option<&[u8]>, Some(&secret[..]))?;
+        let secret = secrets
+            .get(FAKE_SALT_SECRET)?
+            .expect("the secret was just stored");
+        Ok(ScramCredentials::fake(username, &secret))
     }
 
     pub fn login(&self, username: &str, password: &str) -> Result<bool, sled::Error> {
+        // Stored passwords are normalized, so one SASLprep refuses cannot
+        // match.
+        let Ok(password) = stringprep::saslprep(password) else {
+            return Ok(false);
+        };
         match self.store.get(username)? {
             Some(val) => {
                 let stored_hash_str = std::str::from_utf8(&val).unwrap();
@@ -55,9 +158,77 @@ impl AuthStore {
                     .is_ok();
                 Ok(is_valid)
             }
+            None => {
+                let dummy_hash = PasswordHash::new(dummy_hash()).unwrap();
+                let _ = Argon2::default().verify_password(password.as_bytes(), &dummy_hash);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn user_exists(&self, username: &str) -> Result<bool, sled::Error> {
+        self.store.contains_key(username)
+    }
+
+    /// Stores the APOP shared secret for an existing user. Returns `false` if
+    /// the user does not exist.
+    pub fn set_apop_secret(&self, username: &str, secret: &str) -> Result<bool, sled::Error> {
+        if !self.store.contains_key(username)? {
+            return Ok(false);
+        }
+        let secrets = self.store.open_tree(APOP_TREE)?;
+        secrets.insert(username, secret.as_bytes())?;
+        Ok(true)
+    }
+
+    /// Checks an APOP digest, the hex MD5 of `timestamp` followed by the
+    /// user's shared secret.
+    pub fn verify_apop(
+        &self,
+        username: &str,
+        timestamp: &str,
+        digest: &str,
+    ) -> Result<bool, sled::Error> {
+        let secrets = self.store.open_tree(APOP_TREE)?;
+        match secrets.get(username)? {
+            Some(secret) => {
+                let mut hasher = Md5::new();
+                hasher.update(timestamp.as_bytes());
+                hasher.update(&secret);
+                let expected: String = hasher
+                    .finalize()
+                    .iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect();
+                Ok(constant_time_eq(
+                    expected.as_bytes(),
+                    digest.to_ascii_lowercase().as_bytes(),
+                ))
+            }
             None => Ok(false),
         }
     }
+
+    /// Routes a user to an upstream server when running as a proxy. The
+    /// user does not need local credentials, since the upstream server
+    /// checks them.
+    pub fn set_route(&self, username: &str, upstream: &str) -> Result<(), sled::Error> {
+        let routes = self.store.open_tree(ROUTES_TREE)?;
+        routes.insert(username, upstream.as_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the upstream server a user is routed to, if any.
+    pub fn route(&self, username: &str) -> Result<Option<String>, sled::Error> {
+        let routes = self.store.open_tree(ROUTES_TREE)?;
+        Ok(routes
+            .get(username)?
+            .map(|upstream| String::from_utf8_lossy(&upstream).into_owned()))
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
 }
 
 #[cfg(test)]
@@ -93,4 +264,150 @@ mod tests {
             "Login with non-existent user should fail"
         );
     }
+
+    #[test]
+    fn test_passwords_are_saslprepped() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth_store = AuthStore::new(db);
+
+        // RFC 4013 section 3: the soft hyphen maps to nothing.
+        auth_store.create_user("user", "I\u{00AD}X").unwrap();
+        assert!(auth_store.login("user", "IX").unwrap());
+        assert!(auth_store.login("user", "I\u{00AD}X").unwrap());
+
+        assert!(matches!(
+            auth_store.create_user("other", "pass\u{0007}"),
+            Err(AuthError::InvalidPassword)
+        ));
+        assert!(!auth_store.user_exists("other").unwrap());
+        assert!(matches!(
+            auth_store.change_password("user", "pass\u{0007}"),
+            Err(AuthError::InvalidPassword)
+        ));
+        assert!(auth_store.login("user", "IX").unwrap());
+    }
+
+    #[test]
+    fn test_scram_credentials() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth_store = AuthStore::new(db);
+
+        assert!(auth_store.scram_credentials("user").unwrap().is_none());
+
+        auth_store.create_user("user", "pencil").unwrap();
+        let creds = auth_store.scram_credentials("user").unwrap().unwrap();
+        assert_eq!(creds.iterations, SCRAM_ITERATIONS);
+        assert_eq!(
+            creds,
+            ScramCredentials::derive("pencil", creds.salt.clone(), creds.iterations).unwrap(),
+            "stored credentials should round-trip"
+        );
+        assert_eq!(
+            ScramCredentials::derive("I\u{00AD}X", creds.salt.clone(), 4096).unwrap(),
+            ScramCredentials::derive("IX", creds.salt.clone(), 4096).unwrap(),
+            "keys are derived from the SASLprep form of the password"
+        );
+        assert!(
+            matches!(
+                ScramCredentials::derive("a\u{0007}b", creds.salt.clone(), 4096),
+                Err(AuthError::InvalidPassword)
+            ),
+            "passwords SASLprep refuses should be an error"
+        );
+
+        let changed = auth_store.change_password("user", "crayon").unwrap();
+        assert!(
+            changed,
+            "Changing an existing user's password should succeed"
+        );
+        let new_creds = auth_store.scram_credentials("user").unwrap().unwrap();
+        assert_ne!(creds.stored_key, new_creds.stored_key);
+        assert!(auth_store.login("user", "crayon").unwrap());
+        assert!(!auth_store.login("user", "pencil").unwrap());
+
+        assert!(!auth_store.change_password("nobody", "crayon").unwrap());
+    }
+
+    #[test]
+    fn test_scram_client_proof() {
+        // Test vector from RFC 7677 section 3.
+        let salt = [
+            0x5b, 0x6d, 0x99, 0x68, 0x9d, 0x12, 0x35, 0x8e, 0xec, 0xa0, 0x4b, 0x14, 0x12, 0x36,
+            0xfa, 0x81,
+        ];
+        let creds = ScramCredentials::derive("pencil", salt.to_vec(), 4096).unwrap();
+        let auth_message = "n=user,r=rOprNGfwEbeRWgbNEkqO,\
+            r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096,\
+            c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0";
+        let client_proof = [
+            0x74, 0x7c, 0xdb, 0x65, 0xaa, 0x56, 0x22, 0x4e, 0x23, 0x52, 0x13, 0x7e, 0x52, 0xd7,
+            0xbd, 0xca, 0xd6, 0xa0, 0xf7, 0x38, 0xdf, 0x30, 0x78, 0x2c, 0xaa, 0x69, 0xa2, 0xcf,
+            0xb0, 0x27, 0x75, 0x54,
+        ];
+        let server_signature = [
+            0xea, 0xba, 0xe2, 0x4d, 0x10, 0x62, 0xdb, 0x75, 0xa9, 0x45, 0x1f, 0xf0, 0xb6, 0xea,
+            0x7e, 0x98, 0xc8, 0x54, 0x65, 0x49, 0xff, 0x74, 0x1e, 0x67, 0x2d, 0x32, 0x51, 0xb2,
+            0x39, 0x7d, 0xe4, 0x6e,
+        ];
+        assert!(creds.verify_client_proof(auth_message.as_bytes(), &client_proof));
+        assert!(!creds.verify_client_proof(b"tampered", &client_proof));
+        assert_eq!(
+            creds.server_signature(auth_message.as_bytes()),
+            server_signature
+        );
+    }
+
+    #[test]
+    fn test_apop_secret_and_verify() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth_store = AuthStore::new(db);
+
+        let set_for_missing_user = auth_store.set_apop_secret("mrose", "tanstaaf").unwrap();
+        assert!(
+            !set_for_missing_user,
+            "Setting a secret for a non-existent user should return false"
+        );
+
+        auth_store.create_user("mrose", "password").unwrap();
+        assert!(auth_store.set_apop_secret("mrose", "tanstaaf").unwrap());
+
+        // Example exchange from RFC 1939 section 7.
+        let timestamp = "<1896.697170952@dbc.mtview.ca.us>";
+        let digest = "c4c9334bac560ecc979e58001b3e22fb";
+        let valid = auth_store.verify_apop("mrose", timestamp, digest).unwrap();
+        assert!(valid, "Digest from RFC 1939 should verify");
+
+        let wrong_timestamp = auth_store
+            .verify_apop("mrose", "<1@example.com>", digest)
+            .unwrap();
+        assert!(
+            !wrong_timestamp,
+            "Digest over another timestamp should fail"
+        );
+
+        let no_secret = auth_store.verify_apop("nobody", timestamp, digest).unwrap();
+        assert!(!no_secret, "User without a secret should fail");
+    }
+
+    #[test]
+    fn test_routes() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth_store = AuthStore::new(db);
+
+        assert_eq!(auth_store.route("alice").unwrap(), None);
+        auth_store
+            .set_route("alice", "pop3s://old.example.com")
+            .unwrap();
+        auth_store
+            .set_route("alice", "pop3s://new.example.com")
+            .unwrap();
+        assert_eq!(
+            auth_store.route("alice").unwrap().as_deref(),
+            Some("pop3s://new.example.com")
+        );
+        assert!(
+            !auth_store.user_exists("alice").unwrap(),
+            "A route should not create a local user"
+        );
+    }
 }
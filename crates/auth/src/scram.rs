@@ -0,0 +1,118 @@
+use hmac::{Hmac, Mac};
+use rand::{RngCore, rngs::OsRng};
+use sha2::{Digest, Sha256};
+
+use crate::AuthError;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Iteration count used when deriving new SCRAM-SHA-256 credentials. RFC 7677
+/// requires at least 4096.
+pub const SCRAM_ITERATIONS: u32 = 4096;
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+/// The SCRAM-SHA-256 verifiers for a user (RFC 5802 section 3). They allow
+/// checking a client proof without storing anything password-equivalent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramCredentials {
+    pub salt: Vec<u8>,
+    pub iterations: u32,
+    pub stored_key: [u8; KEY_LEN],
+    pub server_key: [u8; KEY_LEN],
+}
+
+impl ScramCredentials {
+    /// Derives credentials for `password` with a fresh random salt.
+    pub fn new(password: &str) -> Result<Self, AuthError> {
+        let mut salt = vec![0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        Self::derive(password, salt, SCRAM_ITERATIONS)
+    }
+
+    /// Derives credentials from the SASLprep form of `password`, as RFC 5802
+    /// section 2.2 requires. Fails if SASLprep refuses the password.
+    pub fn derive(password: &str, salt: Vec<u8>, iterations: u32) -> Result<Self, AuthError> {
+        let password = stringprep::saslprep(password).map_err(|_| AuthError::InvalidPassword)?;
+        let mut salted_password = [0u8; KEY_LEN];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(client_key).into();
+        let server_key = hmac(&salted_password, b"Server Key");
+        Ok(Self {
+            salt,
+            iterations,
+            stored_key,
+            server_key,
+        })
+    }
+
+    /// Stand-in credentials for a user that does not exist. The salt is
+    /// derived from `secret` and the username, so it is the same on every
+    /// attempt like a real user's, and the keys are random, so no proof
+    /// matches them.
+    pub(crate) fn fake(username: &str, secret: &[u8]) -> Self {
+        let salt = hmac(secret, username.as_bytes())[..SALT_LEN].to_vec();
+        let mut stored_key = [0u8; KEY_LEN];
+        let mut server_key = [0u8; KEY_LEN];
+        OsRng.fill_bytes(&mut stored_key);
+        OsRng.fill_bytes(&mut server_key);
+        Self {
+            salt,
+            iterations: SCRAM_ITERATIONS,
+            stored_key,
+            server_key,
+        }
+    }
+
+    /// Checks a ClientProof against the AuthMessage it was computed over.
+    pub fn verify_client_proof(&self, auth_message: &[u8], client_proof: &[u8]) -> bool {
+        if client_proof.len() != KEY_LEN {
+            return false;
+        }
+        let client_signature = hmac(&self.stored_key, auth_message);
+        let client_key: Vec<u8> = client_proof
+            .iter()
+            .zip(client_signature)
+            .map(|(p, s)| p ^ s)
+            .collect();
+        let stored_key: [u8; KEY_LEN] = Sha256::digest(&client_key).into();
+        crate::constant_time_eq(&stored_key, &self.stored_key)
+    }
+
+    /// Computes the ServerSignature the client uses to authenticate us.
+    pub fn server_signature(&self, auth_message: &[u8]) -> [u8; KEY_LEN] {
+        hmac(&self.server_key, auth_message)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + 2 * KEY_LEN + self.salt.len());
+        bytes.extend_from_slice(&self.iterations.to_be_bytes());
+        bytes.extend_from_slice(&self.stored_key);
+        bytes.extend_from_slice(&self.server_key);
+        bytes.extend_from_slice(&self.salt);
+        bytes
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= 4 + 2 * KEY_LEN {
+            return None;
+        }
+        let (iterations, rest) = bytes.split_at(4);
+        let (stored_key, rest) = rest.split_at(KEY_LEN);
+        let (server_key, salt) = rest.split_at(KEY_LEN);
+        Some(Self {
+            salt: salt.to_vec(),
+            iterations: u32::from_be_bytes(iterations.try_into().ok()?),
+            stored_key: stored_key.try_into().ok()?,
+            server_key: server_key.try_into().ok()?,
+        })
+    }
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> [u8; KEY_LEN] {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
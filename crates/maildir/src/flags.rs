@@ -0,0 +1,68 @@
+use std::collections::BTreeSet;
+
+/// A flag from the `:2,` info part of a Maildir filename.
+///
+/// The variants are in the ASCII order of their letters, so iterating a
+/// `BTreeSet<Flag>` yields them in the order the Maildir spec writes them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Flag {
+    /// `D`: the user considers the message a draft.
+    Draft,
+    /// `F`: flagged for urgent or special attention.
+    Flagged,
+    /// `P`: resent, forwarded or bounced to someone else.
+    Passed,
+    /// `R`: replied to.
+    Replied,
+    /// `S`: the user has viewed the message.
+    Seen,
+    /// `T`: marked for later removal.
+    Trashed,
+    /// A lowercase letter whose meaning is up to the software using it.
+    Custom(char),
+}
+
+impl Flag {
+    pub fn from_char(c: char) -> Option<Flag> {
+        match c {
+            'D' => Some(Flag::Draft),
+            'F' => Some(Flag::Flagged),
+            'P' => Some(Flag::Passed),
+            'R' => Some(Flag::Replied),
+            'S' => Some(Flag::Seen),
+            'T' => Some(Flag::Trashed),
+            'a'..='z' => Some(Flag::Custom(c)),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(self) -> char {
+        match self {
+            Flag::Draft => 'D',
+            Flag::Flagged => 'F',
+            Flag::Passed => 'P',
+            Flag::Replied => 'R',
+            Flag::Seen => 'S',
+            Flag::Trashed => 'T',
+            Flag::Custom(c) => c,
+        }
+    }
+}
+
+/// Splits a filename into its unique part and its flags. Names without
+/// `:2,` info, such as those in `new/`, have no flags; letters that are not
+/// flags are dropped.
+pub(crate) fn parse_filename(filename: &str) -> (&str, BTreeSet<Flag>) {
+    let (unique, info) = filename.split_once(':').unwrap_or((filename, ""));
+    let flags = match info.strip_prefix("2,") {
+        Some(flags) => flags.chars().filter_map(Flag::from_char).collect(),
+        None => BTreeSet::new(),
+    };
+    (unique, flags)
+}
+
+/// Builds the filename a message has in `cur/` with the given flags.
+pub(crate) fn format_filename(unique: &str, flags: &BTreeSet<Flag>) -> String {
+    let flags: String = flags.iter().map(|flag| flag.as_char()).collect();
+    format!("{}:2,{}", unique, flags)
+}
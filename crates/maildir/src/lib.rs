@@ -1,6 +1,15 @@
+mod flags;
+mod uidl;
+
+pub use flags::Flag;
+
 use std::{
-    fs, io,
+    collections::BTreeSet,
+    fs,
+    io::{self, Read, Write},
     path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use thiserror::Error;
@@ -26,41 +35,202 @@ pub enum MailDirError {
 pub struct MailDir {
     mailbox_new: PathBuf,
     mailbox_cur: PathBuf,
+    mailbox_tmp: PathBuf,
+    uidl_index: PathBuf,
 }
 
+#[derive(Clone)]
 pub struct MailEntry {
     pub path: PathBuf,
+    /// Size of the message with CRLF line endings, as sent by RETR.
     pub size: u64,
     pub filename: String,
+    /// The POP3 unique-id, from the mailbox's UIDL index.
     pub uidl: String,
+    /// The flags from the filename's `:2,` info.
+    pub flags: BTreeSet<Flag>,
+    /// When the message was delivered, from the timestamp that starts its
+    /// filename, or its modification time if the name has none.
+    pub delivered: SystemTime,
 }
 
 impl MailEntry {
-    pub fn read(&self) -> Result<String, MailDirError> {
-        fs::read_to_string(&self.path).map_err(MailDirError::IoError)
+    /// Opens the message for reading, so it can be streamed rather than
+    /// loaded into memory.
+    pub fn open(&self) -> Result<fs::File, MailDirError> {
+        fs::File::open(&self.path).map_err(MailDirError::IoError)
     }
 
     pub fn delete(&self) -> Result<(), MailDirError> {
         fs::remove_file(&self.path).map_err(MailDirError::IoError)
     }
+
+    /// Marks the message as seen, the way a Maildir reader does once it has
+    /// been retrieved: it is moved to `cur/` with the `S` flag added.
+    pub fn mark_seen(&mut self) -> Result<(), MailDirError> {
+        self.add_flags([Flag::Seen])
+    }
+
+    /// Replaces the message's flags.
+    pub fn set_flags(&mut self, flags: impl IntoIterator<Item = Flag>) -> Result<(), MailDirError> {
+        let flags: BTreeSet<Flag> = flags.into_iter().collect();
+        self.update_flags(|current| *current = flags.clone())
+    }
+
+    pub fn add_flags(&mut self, flags: impl IntoIterator<Item = Flag>) -> Result<(), MailDirError> {
+        let flags: Vec<Flag> = flags.into_iter().collect();
+        self.update_flags(|current| current.extend(&flags))
+    }
+
+    pub fn remove_flags(
+        &mut self,
+        flags: impl IntoIterator<Item = Flag>,
+    ) -> Result<(), MailDirError> {
+        let flags: Vec<Flag> = flags.into_iter().collect();
+        self.update_flags(|current| current.retain(|flag| !flags.contains(flag)))
+    }
+
+    /// Applies `update` to the flags and renames the message into `cur/`
+    /// to match. The unique part of the name, and so the UIDL, is kept.
+    ///
+    /// A rename is atomic, so readers always see the message under one name
+    /// or the other. If another process has renamed it in the meantime, it
+    /// is found again under its new name and `update` is applied to the
+    /// flags that process left, so neither change is lost.
+    fn update_flags(&mut self, update: impl Fn(&mut BTreeSet<Flag>)) -> Result<(), MailDirError> {
+        const ATTEMPTS: usize = 5;
+        for _ in 0..ATTEMPTS {
+            let mut flags = self.flags.clone();
+            update(&mut flags);
+            let (unique, _) = flags::parse_filename(&self.filename);
+            let filename = flags::format_filename(unique, &flags);
+            let path = self.mailbox()?.join("cur").join(&filename);
+            if path == self.path {
+                self.flags = flags;
+                return Ok(());
+            }
+            match fs::rename(&self.path, &path) {
+                Ok(()) => {
+                    self.path = path;
+                    self.filename = filename;
+                    self.flags = flags;
+                    return Ok(());
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => self.relocate()?,
+                Err(e) => return Err(MailDirError::IoError(e)),
+            }
+        }
+        Err(MailDirError::MailEntryNotFound(self.filename.clone()))
+    }
+
+    /// Finds the message again after another process renamed it, picking
+    /// up the flags it now has.
+    fn relocate(&mut self) -> Result<(), MailDirError> {
+        let mailbox = self.mailbox()?.to_path_buf();
+        let (unique, _) = flags::parse_filename(&self.filename);
+        let unique = unique.to_string();
+        for dir in ["cur", "new"] {
+            let Ok(read_dir) = fs::read_dir(mailbox.join(dir)) else {
+                continue;
+            };
+            for entry in read_dir.flatten() {
+                let Ok(filename) = entry.file_name().into_string() else {
+                    continue;
+                };
+                let (found, flags) = flags::parse_filename(&filename);
+                if found == unique {
+                    self.flags = flags;
+                    self.path = entry.path();
+                    self.filename = filename;
+                    return Ok(());
+                }
+            }
+        }
+        Err(MailDirError::MailEntryNotFound(self.filename.clone()))
+    }
+
+    /// The Maildir holding the message, the parent of its `new/` or `cur/`.
+    fn mailbox(&self) -> Result<&Path, MailDirError> {
+        self.path
+            .parent()
+            .and_then(Path::parent)
+            .ok_or_else(|| MailDirError::MailEntryNotFound(self.path.display().to_string()))
+    }
 }
 
 impl MailDir {
     pub fn new(username: &str) -> io::Result<Self> {
         let mailbox_new = PathBuf::from(format!("{MAILDIR_BASE}/{username}/new"));
         let mailbox_cur = PathBuf::from(format!("{MAILDIR_BASE}/{username}/cur"));
+        let mailbox_tmp = PathBuf::from(format!("{MAILDIR_BASE}/{username}/tmp"));
+        let uidl_index = PathBuf::from(format!("{MAILDIR_BASE}/{username}/{}", uidl::INDEX_FILE));
         Ok(Self {
             mailbox_new,
             mailbox_cur,
+            mailbox_tmp,
+            uidl_index,
         })
     }
 
-    pub fn list_messages(&self) -> Vec<MailEntry> {
+    /// Lists the messages in `new/` and `cur/`, oldest delivery first, with
+    /// their UIDLs and sizes taken from the mailbox's UIDL index. Messages
+    /// seen for the first time are read to size them and added to the index;
+    /// a message that cannot be read is an error.
+    ///
+    /// The order only depends on the messages themselves, so message numbers
+    /// stay the same from one session to the next while nothing is added or
+    /// removed. Messages delivered at the same time are ordered by name.
+    pub fn list_messages(&self) -> io::Result<Vec<MailEntry>> {
         let mut entries = Vec::new();
         scan_dir(&self.mailbox_new, &mut entries);
         scan_dir(&self.mailbox_cur, &mut entries);
-        entries
+        entries.sort_by(|a, b| {
+            let a_unique = flags::parse_filename(&a.filename).0;
+            let b_unique = flags::parse_filename(&b.filename).0;
+            (a.delivered, a_unique).cmp(&(b.delivered, b_unique))
+        });
+        let mut index = uidl::UidlIndex::load(&self.uidl_index)?;
+        index.assign(&mut entries)?;
+        index.save(&self.uidl_index, &self.mailbox_tmp)?;
+        Ok(entries)
     }
+
+    /// Delivers a message the Maildir way: it is written and synced under
+    /// `tmp/`, then renamed into `new/`, so readers never see a partial
+    /// message. Returns the path of the delivered message.
+    pub fn deliver(&self, msg: &[u8]) -> io::Result<PathBuf> {
+        let filename = unique_filename();
+        let tmp_path = self.mailbox_tmp.join(&filename);
+        let mut file = fs::File::create_new(&tmp_path)?;
+        if let Err(e) = file.write_all(msg).and_then(|_| file.sync_all()) {
+            let _ = fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        let new_path = self.mailbox_new.join(&filename);
+        fs::rename(&tmp_path, &new_path)?;
+        Ok(new_path)
+    }
+}
+
+/// Builds a Maildir filename of the form `time.MusecPpidQn.host`, unique
+/// across processes and deliveries.
+fn unique_filename() -> String {
+    static DELIVERIES: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let hostname = gethostname::gethostname()
+        .to_string_lossy()
+        .replace('/', "\\057")
+        .replace(':', "\\072");
+    format!(
+        "{}.M{}P{}Q{}.{}",
+        now.as_secs(),
+        now.subsec_micros(),
+        std::process::id(),
+        DELIVERIES.fetch_add(1, Ordering::Relaxed),
+        hostname
+    )
 }
 
 fn scan_dir(dir: &Path, entries: &mut Vec<MailEntry>) {
@@ -75,15 +245,234 @@ fn scan_dir(dir: &Path, entries: &mut Vec<MailEntry>) {
         };
         let path = e.path();
         if path.is_file() {
-            let size = fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
-            let filename = e.file_name().into_string().unwrap_or_default();
-            let uidl = filename.split(':').next().unwrap_or(&filename).to_string();
+            // Flag changes rename a message after its name and the UIDL
+            // index is keyed by it, so a name that cannot be held as a
+            // string is never touched.
+            let Ok(filename) = e.file_name().into_string() else {
+                println!("skipping message with a non-UTF-8 name: {}", path.display());
+                continue;
+            };
+            let (unique, flags) = flags::parse_filename(&filename);
+            let uidl = unique.to_string();
+            let delivered = delivery_time(unique)
+                .or_else(|| e.metadata().and_then(|m| m.modified()).ok())
+                .unwrap_or(UNIX_EPOCH);
             entries.push(MailEntry {
                 path,
-                size,
+                // Filled in from the UIDL index by `list_messages`.
+                size: 0,
                 filename,
                 uidl,
+                flags,
+                delivered,
             });
         }
     }
 }
+
+/// Reads the delivery time from a unique name of the usual `time.MusecP...`
+/// form. The microseconds are optional, as older delivery agents leave them
+/// out.
+fn delivery_time(unique: &str) -> Option<SystemTime> {
+    let mut parts = unique.split('.');
+    let secs = parts.next()?;
+    if secs.is_empty() || !secs.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut time = UNIX_EPOCH.checked_add(Duration::from_secs(secs.parse().ok()?))?;
+    if let Some(usecs) = parts.next().and_then(|part| part.strip_prefix('M')) {
+        let digits = usecs.bytes().take_while(u8::is_ascii_digit).count();
+        if let Ok(usecs) = usecs[..digits].parse::<u32>()
+            && usecs < 1_000_000
+        {
+            time += Duration::from_micros(usecs.into());
+        }
+    }
+    Some(time)
+}
+
+/// Tracks the size of a message once every line ending is converted to CRLF
+/// and an unterminated final line is terminated, as POP3 transmits it.
+#[derive(Default)]
+struct CanonicalSize {
+    size: u64,
+    last: Option<u8>,
+}
+
+impl CanonicalSize {
+    fn update(&mut self, chunk: &[u8]) {
+        for &b in chunk {
+            self.size += match (b, self.last) {
+                (b'\n', Some(b'\r')) => 1,
+                (b'\n', _) => 2,
+                _ => 1,
+            };
+            self.last = Some(b);
+        }
+    }
+
+    fn finish(self) -> u64 {
+        match self.last {
+            Some(b'\n') | None => self.size,
+            Some(_) => self.size + 2,
+        }
+    }
+}
+
+/// Returns the size of a message in canonical CRLF form.
+pub fn canonical_size(msg: &[u8]) -> u64 {
+    let mut size = CanonicalSize::default();
+    size.update(msg);
+    size.finish()
+}
+
+pub(crate) fn canonical_file_size(path: &Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut size = CanonicalSize::default();
+    let mut buf = [0u8; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Ok(size.finish());
+        }
+        size.update(&buf[..n]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flags_survive_concurrent_renames() {
+        let mailbox = std::env::temp_dir().join(format!("maildir-flags-{}", std::process::id()));
+        for dir in ["new", "cur", "tmp"] {
+            fs::create_dir_all(mailbox.join(dir)).unwrap();
+        }
+        fs::write(mailbox.join("cur/1000.abc.host:2,Sa"), "Subject: x\n\nhi\n").unwrap();
+        let mut entries = Vec::new();
+        scan_dir(&mailbox.join("cur"), &mut entries);
+        let mut entry = entries.pop().unwrap();
+        assert_eq!(entry.uidl, "1000.abc.host");
+        assert_eq!(entry.flags, BTreeSet::from([Flag::Seen, Flag::Custom('a')]));
+
+        entry.add_flags([Flag::Replied, Flag::Draft]).unwrap();
+        assert_eq!(entry.filename, "1000.abc.host:2,DRSa");
+        assert!(entry.path.exists());
+
+        // Another process flags the message behind our back; our change is
+        // applied on top of theirs.
+        fs::rename(&entry.path, mailbox.join("cur/1000.abc.host:2,DFRSa")).unwrap();
+        entry.remove_flags([Flag::Seen, Flag::Custom('a')]).unwrap();
+        assert_eq!(entry.filename, "1000.abc.host:2,DFR");
+        assert_eq!(entry.uidl, "1000.abc.host", "the UIDL should not change");
+
+        entry.set_flags([]).unwrap();
+        assert_eq!(
+            fs::read_dir(mailbox.join("cur"))
+                .unwrap()
+                .map(|e| e.unwrap().file_name())
+                .collect::<Vec<_>>(),
+            vec!["1000.abc.host:2,"]
+        );
+
+        fs::remove_file(&entry.path).unwrap();
+        assert!(matches!(
+            entry.mark_seen(),
+            Err(MailDirError::MailEntryNotFound(_))
+        ));
+        fs::remove_dir_all(&mailbox).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_names_are_left_alone() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
+        let base = std::env::temp_dir().join(format!("maildir-non-utf8-{}", std::process::id()));
+        let maildir = MailDir {
+            mailbox_new: base.join("new"),
+            mailbox_cur: base.join("cur"),
+            mailbox_tmp: base.join("tmp"),
+            uidl_index: base.join(uidl::INDEX_FILE),
+        };
+        for dir in ["new", "cur", "tmp"] {
+            fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        let odd = [
+            base.join("new")
+                .join(OsStr::from_bytes(b"1700000000.M1P1.\xff")),
+            base.join("new")
+                .join(OsStr::from_bytes(b"1700000000.M2P1.\xfe")),
+        ];
+        for (i, path) in odd.iter().enumerate() {
+            fs::write(path, format!("Subject: {}\n\n", i)).unwrap();
+        }
+        fs::write(base.join("new/1700000000.M3P1.host"), "Subject: x\n\n").unwrap();
+
+        // Retrieving every message and marking it seen, as QUIT does.
+        let mut entries = maildir.list_messages().unwrap();
+        assert_eq!(entries.len(), 1);
+        for entry in &mut entries {
+            entry.mark_seen().unwrap();
+        }
+        for (i, path) in odd.iter().enumerate() {
+            assert_eq!(
+                fs::read(path).unwrap(),
+                format!("Subject: {}\n\n", i).as_bytes()
+            );
+        }
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn test_list_messages_oldest_first() {
+        let at =
+            |secs, usecs| UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_micros(usecs);
+        assert_eq!(
+            delivery_time("1700000000.M20P1Q2.host"),
+            Some(at(1700000000, 20))
+        );
+        assert_eq!(
+            delivery_time("1700000000.123_4.host"),
+            Some(at(1700000000, 0))
+        );
+        assert_eq!(delivery_time("abc.host"), None);
+
+        let base = std::env::temp_dir().join(format!("maildir-order-{}", std::process::id()));
+        let maildir = MailDir {
+            mailbox_new: base.join("new"),
+            mailbox_cur: base.join("cur"),
+            mailbox_tmp: base.join("tmp"),
+            uidl_index: base.join(uidl::INDEX_FILE),
+        };
+        for dir in ["new", "cur", "tmp"] {
+            fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        for name in [
+            "new/1700000300.M1P1.host",
+            "cur/1700000100.M500P1.host:2,S",
+            "new/1700000100.M40P1.host",
+            "cur/1600000000.old.host:2,",
+        ] {
+            fs::write(base.join(name), "Subject: x\n\n").unwrap();
+        }
+
+        let names: Vec<String> = maildir
+            .list_messages()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.uidl)
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "1600000000.old.host",
+                "1700000100.M40P1.host",
+                "1700000100.M500P1.host",
+                "1700000300.M1P1.host",
+            ]
+        );
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write as _,
+    fs, io,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{MailEntry, flags};
+
+/// The index file, kept in the root of each Maildir.
+pub(crate) const INDEX_FILE: &str = "pop3-uidlist";
+
+/// RFC 1939 section 7: a unique-id is 1 to 70 characters in 0x21 to 0x7E.
+const MAX_UIDL_LEN: usize = 70;
+
+/// Remembers the UIDL given to each message, and its size in CRLF form,
+/// keyed by the unique part of its filename, which stays the same when
+/// flags are changed. Messages never change once delivered, so the size is
+/// only computed the first time a message is seen.
+///
+/// The file holds a header line `V2 <validity> <next>` and then one
+/// `<uidl> <size> <unique>` line per message. Version 1 files, which have
+/// no sizes, are still read. Generated UIDLs are `<validity>.<n>`, where
+/// `n` only ever grows, and `validity` is the time the index was created,
+/// so even an index that is lost and rebuilt does not hand out a UIDL
+/// again.
+pub(crate) struct UidlIndex {
+    validity: u64,
+    next: u64,
+    uidls: HashMap<String, Indexed>,
+    changed: bool,
+}
+
+struct Indexed {
+    uidl: String,
+    /// Unknown for messages read from a version 1 index.
+    size: Option<u64>,
+}
+
+impl UidlIndex {
+    /// Loads the index, starting a new one if it is missing. A corrupt index
+    /// is an error: replacing it would give every message a new UIDL and
+    /// make clients download the whole mailbox again.
+    pub(crate) fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(contents) => Self::parse(&contents).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("corrupt UIDL index {}", path.display()),
+                )
+            }),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn new() -> Self {
+        let validity = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        Self {
+            validity,
+            next: 1,
+            uidls: HashMap::new(),
+            changed: true,
+        }
+    }
+
+    fn parse(contents: &str) -> Option<Self> {
+        let mut lines = contents.lines();
+        let mut header = lines.next()?.split(' ');
+        let with_sizes = match header.next()? {
+            "V1" => false,
+            "V2" => true,
+            _ => return None,
+        };
+        let validity = u64::from_str_radix(header.next()?, 16).ok()?;
+        let next = header.next()?.parse().ok()?;
+        let uidls = lines
+            .map(|line| {
+                let (uidl, rest) = line.split_once(' ')?;
+                let (size, unique) = if with_sizes {
+                    let (size, unique) = rest.split_once(' ')?;
+                    let size = match size {
+                        "-" => None,
+                        size => Some(size.parse().ok()?),
+                    };
+                    (size, unique)
+                } else {
+                    (None, rest)
+                };
+                let indexed = Indexed {
+                    uidl: uidl.to_string(),
+                    size,
+                };
+                Some((unique.to_string(), indexed))
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            validity,
+            next,
+            uidls,
+            changed: false,
+        })
+    }
+
+    /// Sets the UIDL and size of every entry, giving new messages a UIDL
+    /// and reading their size on first sight, and forgets messages that are
+    /// gone.
+    ///
+    /// A message whose unique name is already a valid UIDL keeps it, so the
+    /// UIDLs clients knew before the index existed stay the same.
+    pub(crate) fn assign(&mut self, entries: &mut [MailEntry]) -> io::Result<()> {
+        let present: HashSet<&str> = entries
+            .iter()
+            .map(|entry| flags::parse_filename(&entry.filename).0)
+            .collect();
+        let before = self.uidls.len();
+        self.uidls
+            .retain(|unique, _| present.contains(unique.as_str()));
+        self.changed |= self.uidls.len() != before;
+
+        let mut in_use: HashSet<String> = self.uidls.values().map(|i| i.uidl.clone()).collect();
+        let mut listed = HashSet::new();
+        for entry in entries.iter_mut() {
+            let unique = flags::parse_filename(&entry.filename).0.to_string();
+            // Two files with the same unique name, such as a copy left in
+            // new/ after the message was moved to cur/, must not share a
+            // UIDL. The second one gets a fresh UIDL that is not indexed.
+            if !listed.insert(unique.clone()) {
+                let uidl = self.generate(&in_use);
+                in_use.insert(uidl.clone());
+                self.changed = true;
+                entry.uidl = uidl;
+                entry.size = crate::canonical_file_size(&entry.path)?;
+                continue;
+            }
+            if let Some(indexed) = self.uidls.get_mut(&unique) {
+                entry.uidl = indexed.uidl.clone();
+                entry.size = match indexed.size {
+                    Some(size) => size,
+                    None => {
+                        let size = crate::canonical_file_size(&entry.path)?;
+                        indexed.size = Some(size);
+                        self.changed = true;
+                        size
+                    }
+                };
+                continue;
+            }
+            let uidl = if is_valid_uidl(&unique) && !in_use.contains(&unique) {
+                unique.clone()
+            } else {
+                self.generate(&in_use)
+            };
+            let size = crate::canonical_file_size(&entry.path)?;
+            in_use.insert(uidl.clone());
+            self.uidls.insert(
+                unique,
+                Indexed {
+                    uidl: uidl.clone(),
+                    size: Some(size),
+                },
+            );
+            self.changed = true;
+            entry.uidl = uidl;
+            entry.size = size;
+        }
+        Ok(())
+    }
+
+    fn generate(&mut self, in_use: &HashSet<String>) -> String {
+        loop {
+            let uidl = format!("{:x}.{}", self.validity, self.next);
+            self.next += 1;
+            if !in_use.contains(&uidl) {
+                return uidl;
+            }
+        }
+    }
+
+    /// Writes the index if it changed, through a file in `tmp_dir` that is
+    /// renamed into place so the index is never seen half written.
+    pub(crate) fn save(&self, path: &Path, tmp_dir: &Path) -> io::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let mut contents = format!("V2 {:x} {}\n", self.validity, self.next);
+        for (unique, indexed) in &self.uidls {
+            let size = match indexed.size {
+                Some(size) => size.to_string(),
+                None => "-".to_string(),
+            };
+            let _ = writeln!(contents, "{} {} {}", indexed.uidl, size, unique);
+        }
+        let tmp_path = tmp_dir.join(format!("{}.{}", INDEX_FILE, std::process::id()));
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, path)
+    }
+}
+
+/// Whether `uidl` may be sent as a unique-id.
+pub(crate) fn is_valid_uidl(uidl: &str) -> bool {
+    (1..=MAX_UIDL_LEN).contains(&uidl.len()) && uidl.bytes().all(|b| (0x21..=0x7e).contains(&b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::BTreeSet, path::PathBuf};
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("maildir-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An entry for a message `a\n`, 3 octets in CRLF form.
+    fn entry(dir: &Path, filename: &str) -> MailEntry {
+        let path = dir.join(filename);
+        fs::write(&path, "a\n").unwrap();
+        MailEntry {
+            path,
+            size: 0,
+            filename: filename.to_string(),
+            uidl: String::new(),
+            flags: BTreeSet::new(),
+            delivered: UNIX_EPOCH,
+        }
+    }
+
+    #[test]
+    fn test_uidls_are_stable_and_never_reused() {
+        let dir = test_dir("uidl");
+        let long = "x".repeat(71);
+        let mut index = UidlIndex::new();
+        let mut entries = vec![
+            entry(&dir, "1000.abc.host"),
+            entry(&dir, &long),
+            entry(&dir, "1001 with spaces"),
+        ];
+        index.assign(&mut entries).unwrap();
+        let uidls: Vec<String> = entries.iter().map(|e| e.uidl.clone()).collect();
+        assert_eq!(uidls[0], "1000.abc.host", "valid names are kept");
+        assert!(uidls[1..].iter().all(|uidl| is_valid_uidl(uidl)));
+        assert_ne!(uidls[1], uidls[2]);
+        assert!(entries.iter().all(|e| e.size == 3));
+
+        // Flag changes rename the files; a message is dropped and a new one
+        // arrives. The index survives a save and load.
+        let path = dir.join(INDEX_FILE);
+        index.save(&path, &dir).unwrap();
+        let mut index = UidlIndex::load(&path).unwrap();
+        let mut entries = vec![
+            entry(&dir, "1000.abc.host:2,S"),
+            entry(&dir, &format!("{}:2,RS", long)),
+            entry(&dir, "1002 with spaces"),
+        ];
+        // Sizes come from the index, not from reading the message again.
+        fs::write(&entries[0].path, "longer\n").unwrap();
+        index.assign(&mut entries).unwrap();
+        assert_eq!(entries[0].uidl, uidls[0]);
+        assert_eq!(entries[0].size, 3);
+        assert_eq!(entries[1].uidl, uidls[1]);
+        assert!(
+            !uidls.contains(&entries[2].uidl),
+            "a new message must not get a UIDL used before"
+        );
+
+        // A version 1 index has no sizes; they are read and saved.
+        fs::write(&path, "V1 10 5\n1000.abc.host 1000.abc.host\n").unwrap();
+        let mut index = UidlIndex::load(&path).unwrap();
+        let mut entries = vec![entry(&dir, "1000.abc.host")];
+        index.assign(&mut entries).unwrap();
+        assert_eq!(
+            (entries[0].uidl.as_str(), entries[0].size),
+            ("1000.abc.host", 3)
+        );
+        index.save(&path, &dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "V2 10 5\n1000.abc.host 3 1000.abc.host\n"
+        );
+
+        fs::write(&path, "garbage").unwrap();
+        let err = UidlIndex::load(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_same_unique_name_gets_distinct_uidls() {
+        let dir = test_dir("uidl-duplicate");
+        let mut index = UidlIndex::new();
+        let mut entries = vec![
+            entry(&dir, "1000.abc.host"),
+            entry(&dir, "1000.abc.host:2,S"),
+        ];
+        index.assign(&mut entries).unwrap();
+        assert_eq!(entries[0].uidl, "1000.abc.host");
+        assert_ne!(entries[0].uidl, entries[1].uidl);
+        assert!(is_valid_uidl(&entries[1].uidl));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
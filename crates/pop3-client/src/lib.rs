@@ -0,0 +1,559 @@
+//! An async POP3 client built on the `pop3-proto` command and response types.
+
+use std::{future::Future, io, sync::Arc, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use md5::{Digest, Md5};
+use pop3_proto::{Command, ResponseCode, StatusIndicator};
+use rustls::{ClientConfig, pki_types::ServerName};
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::{TcpStream, ToSocketAddrs},
+};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+/// How long connecting, the TLS handshake, or a single read or write may
+/// take before it fails with `io::ErrorKind::TimedOut`.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The longest response line accepted, including its terminator. RFC 5322
+/// limits message lines to 1000 octets; this leaves room for servers that
+/// do not.
+pub const MAX_LINE: usize = 64 * 1024;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    /// The server answered `-ERR`, with its RFC 2449 response code if it
+    /// sent one.
+    #[error("server error: {message}")]
+    Server {
+        code: Option<ResponseCode>,
+        message: String,
+    },
+    /// The server sent something that is not a valid POP3 response.
+    #[error("protocol error: {0}")]
+    Protocol(String),
+    #[error("server does not support APOP")]
+    ApopUnavailable,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// A POP3 session. Methods map one to one onto POP3 commands and must be
+/// used in the state the command is valid in; the server's `-ERR` is
+/// returned as `Error::Server` otherwise.
+pub struct Client<S> {
+    stream: BufReader<S>,
+    greeting: String,
+    timeout: Duration,
+}
+
+impl Client<TcpStream> {
+    /// Connects to a plaintext POP3 port, usually 110.
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let stream = timeout(DEFAULT_TIMEOUT, TcpStream::connect(addr)).await?;
+        Client::from_stream(stream).await
+    }
+
+    /// Connects to a POP3S port, usually 995, negotiating TLS before the
+    /// greeting is read.
+    pub async fn connect_tls(
+        addr: impl ToSocketAddrs,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Result<Client<TlsStream<TcpStream>>> {
+        let stream = timeout(DEFAULT_TIMEOUT, TcpStream::connect(addr)).await?;
+        let stream = timeout(
+            DEFAULT_TIMEOUT,
+            TlsConnector::from(config).connect(server_name, stream),
+        )
+        .await?;
+        Client::from_stream(stream).await
+    }
+}
+
+impl<S> Client<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Starts a session over an established connection by reading the
+    /// server greeting. Reads and writes time out after `DEFAULT_TIMEOUT`
+    /// until `set_timeout` is called.
+    pub async fn from_stream(stream: S) -> Result<Self> {
+        let mut client = Client {
+            stream: BufReader::new(stream),
+            greeting: String::new(),
+            timeout: DEFAULT_TIMEOUT,
+        };
+        client.greeting = client.read_status().await?;
+        Ok(client)
+    }
+
+    /// The text of the server's `+OK` greeting.
+    pub fn greeting(&self) -> &str {
+        &self.greeting
+    }
+
+    /// Sets how long each later read, write or TLS handshake may take.
+    pub fn set_timeout(&mut self, duration: Duration) {
+        self.timeout = duration;
+    }
+
+    /// Upgrades the connection with STLS (RFC 2595). The session stays in
+    /// the Authorization state and no new greeting is sent.
+    pub async fn stls(
+        mut self,
+        server_name: ServerName<'static>,
+        config: Arc<ClientConfig>,
+    ) -> Result<Client<TlsStream<S>>> {
+        self.command(Command::Stls).await?;
+        let stream = timeout(
+            self.timeout,
+            TlsConnector::from(config).connect(server_name, self.stream.into_inner()),
+        )
+        .await?;
+        Ok(Client {
+            stream: BufReader::new(stream),
+            greeting: self.greeting,
+            timeout: self.timeout,
+        })
+    }
+
+    /// Lists the server's capabilities (RFC 2449).
+    pub async fn capa(&mut self) -> Result<Vec<String>> {
+        let body = self.multi_line(Command::Capa).await?;
+        Ok(lines(&body)?.map(str::to_string).collect())
+    }
+
+    /// Logs in with USER and PASS.
+    pub async fn login(&mut self, username: &str, password: &str) -> Result<()> {
+        self.command(Command::User(username.to_string())).await?;
+        self.command(Command::Pass(password.to_string())).await?;
+        Ok(())
+    }
+
+    /// Logs in with APOP, using the timestamp from the greeting.
+    pub async fn apop(&mut self, username: &str, secret: &str) -> Result<()> {
+        let digest = apop_digest(&self.greeting, secret).ok_or(Error::ApopUnavailable)?;
+        self.command(Command::Apop(username.to_string(), digest))
+            .await?;
+        Ok(())
+    }
+
+    /// Runs a SASL exchange (RFC 5034). `respond` is given each decoded
+    /// server challenge and returns the client's response.
+    pub async fn auth<F>(
+        &mut self,
+        mechanism: &str,
+        initial_response: Option<&[u8]>,
+        mut respond: F,
+    ) -> Result<()>
+    where
+        F: FnMut(&[u8]) -> Vec<u8>,
+    {
+        // "=" stands for an initial response of zero length.
+        let initial_response = initial_response.map(|response| match response {
+            [] => "=".to_string(),
+            response => BASE64.encode(response),
+        });
+        self.send(&Command::Auth(mechanism.to_string(), initial_response))
+            .await?;
+        loop {
+            match self.read_response().await? {
+                StatusIndicator::Ok(_) => return Ok(()),
+                StatusIndicator::Continue(challenge) => {
+                    let challenge = BASE64
+                        .decode(challenge.trim())
+                        .map_err(|_| Error::Protocol("invalid base64 challenge".to_string()))?;
+                    let response = BASE64.encode(respond(&challenge)) + "\r\n";
+                    self.write(response.as_bytes()).await?;
+                }
+                resp => return Err(server_error(resp)),
+            }
+        }
+    }
+
+    /// Logs in with SASL PLAIN (RFC 4616).
+    pub async fn auth_plain(&mut self, username: &str, password: &str) -> Result<()> {
+        let response = format!("\0{}\0{}", username, password);
+        self.auth("PLAIN", Some(response.as_bytes()), |_| Vec::new())
+            .await
+    }
+
+    /// Returns the number of messages in the maildrop and their total size.
+    pub async fn stat(&mut self) -> Result<(u64, u64)> {
+        let status = self.command(Command::Stat).await?;
+        let (count, octets) = parse_pair(&status)?;
+        Ok((count, parse_number(octets)?))
+    }
+
+    /// Returns the number and size of every message.
+    pub async fn list(&mut self) -> Result<Vec<(u64, u64)>> {
+        let body = self.multi_line(Command::List(None)).await?;
+        lines(&body)?
+            .map(|line| {
+                let (id, size) = parse_pair(line)?;
+                Ok((id, parse_number(size)?))
+            })
+            .collect()
+    }
+
+    /// Returns the size of one message.
+    pub async fn list_one(&mut self, id: u64) -> Result<u64> {
+        let status = self.command(Command::List(Some(id))).await?;
+        parse_number(parse_pair(&status)?.1)
+    }
+
+    /// Returns the number and unique id of every message.
+    pub async fn uidl(&mut self) -> Result<Vec<(u64, String)>> {
+        let body = self.multi_line(Command::Uidl(None)).await?;
+        lines(&body)?
+            .map(|line| {
+                let (id, uidl) = parse_pair(line)?;
+                Ok((id, uidl.to_string()))
+            })
+            .collect()
+    }
+
+    /// Returns the unique id of one message.
+    pub async fn uidl_one(&mut self, id: u64) -> Result<String> {
+        let status = self.command(Command::Uidl(Some(id))).await?;
+        Ok(parse_pair(&status)?.1.to_string())
+    }
+
+    /// Downloads a message. Lines end in CRLF and dot-stuffing is removed.
+    pub async fn retr(&mut self, id: u64) -> Result<Vec<u8>> {
+        self.multi_line(Command::Retr(id)).await
+    }
+
+    /// Downloads the headers and the first `lines` body lines of a message.
+    pub async fn top(&mut self, id: u64, lines: u64) -> Result<Vec<u8>> {
+        self.multi_line(Command::Top(id, lines)).await
+    }
+
+    pub async fn dele(&mut self, id: u64) -> Result<()> {
+        self.command(Command::Dele(id)).await?;
+        Ok(())
+    }
+
+    pub async fn rset(&mut self) -> Result<()> {
+        self.command(Command::Rset).await?;
+        Ok(())
+    }
+
+    pub async fn noop(&mut self) -> Result<()> {
+        self.command(Command::Noop).await?;
+        Ok(())
+    }
+
+    /// Ends the session. Messages marked with DELE are only removed once the
+    /// server has acknowledged QUIT.
+    pub async fn quit(mut self) -> Result<()> {
+        self.command(Command::Quit).await?;
+        let _ = self.stream.shutdown().await;
+        Ok(())
+    }
+
+    /// Gives up the session and returns the connection, for example to
+    /// relay it elsewhere. The buffer holds anything the server sent that
+    /// has not been read yet.
+    pub fn into_inner(self) -> BufReader<S> {
+        self.stream
+    }
+
+    async fn send(&mut self, cmd: &Command) -> Result<()> {
+        self.write(format!("{}\r\n", cmd).as_bytes()).await
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let stream = &mut self.stream;
+        timeout(self.timeout, async move {
+            stream.write_all(data).await?;
+            stream.flush().await
+        })
+        .await
+    }
+
+    /// Sends a command and returns the text of its `+OK` response.
+    async fn command(&mut self, cmd: Command) -> Result<String> {
+        self.send(&cmd).await?;
+        self.read_status().await
+    }
+
+    /// Sends a command with a multi-line response and returns the body.
+    async fn multi_line(&mut self, cmd: Command) -> Result<Vec<u8>> {
+        self.command(cmd).await?;
+        let mut body = Vec::new();
+        let mut line = Vec::new();
+        loop {
+            self.read_line(&mut line).await?;
+            let content = line.strip_suffix(b"\n").unwrap_or(&line);
+            let content = content.strip_suffix(b"\r").unwrap_or(content);
+            if content == b"." {
+                return Ok(body);
+            }
+            body.extend_from_slice(content.strip_prefix(b".").unwrap_or(content));
+            body.extend_from_slice(b"\r\n");
+        }
+    }
+
+    /// Reads one line of at most `MAX_LINE` octets into `line`.
+    async fn read_line(&mut self, line: &mut Vec<u8>) -> Result<()> {
+        line.clear();
+        let mut limited = (&mut self.stream).take(MAX_LINE as u64);
+        let read = timeout(self.timeout, limited.read_until(b'\n', line)).await?;
+        if read == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        if read == MAX_LINE && !line.ends_with(b"\n") {
+            return Err(Error::Protocol(format!(
+                "line longer than {} octets",
+                MAX_LINE
+            )));
+        }
+        Ok(())
+    }
+
+    async fn read_response(&mut self) -> Result<StatusIndicator> {
+        let mut line = Vec::new();
+        self.read_line(&mut line).await?;
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches(['\r', '\n']);
+        StatusIndicator::parse(line)
+            .ok_or_else(|| Error::Protocol(format!("unexpected response: {}", line)))
+    }
+
+    /// Reads a single-line response, turning `-ERR` into an error.
+    async fn read_status(&mut self) -> Result<String> {
+        match self.read_response().await? {
+            StatusIndicator::Ok(msg) => Ok(msg),
+            resp => Err(server_error(resp)),
+        }
+    }
+}
+
+/// Runs an I/O operation, failing with `io::ErrorKind::TimedOut` if it
+/// takes longer than `duration`.
+async fn timeout<T>(
+    duration: Duration,
+    operation: impl Future<Output = io::Result<T>>,
+) -> Result<T> {
+    match tokio::time::timeout(duration, operation).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+fn server_error(resp: StatusIndicator) -> Error {
+    match resp {
+        StatusIndicator::Err(message) => Error::Server {
+            code: None,
+            message,
+        },
+        StatusIndicator::ErrCode(code, message) => Error::Server {
+            code: Some(code),
+            message,
+        },
+        resp => Error::Protocol(format!(
+            "unexpected response: {}",
+            resp.to_string().trim_end()
+        )),
+    }
+}
+
+/// Computes the APOP digest for the `<...>` timestamp in a greeting.
+fn apop_digest(greeting: &str, secret: &str) -> Option<String> {
+    let start = greeting.find('<')?;
+    let end = start + greeting[start..].find('>')?;
+    let mut hasher = Md5::new();
+    hasher.update(&greeting[start..=end]);
+    hasher.update(secret);
+    Some(
+        hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect(),
+    )
+}
+
+/// Splits a multi-line listing into its lines. A listing that is not UTF-8
+/// is an error rather than an empty list, which callers would take to mean
+/// the mailbox is empty.
+fn lines(body: &[u8]) -> Result<impl Iterator<Item = &str>> {
+    let body = std::str::from_utf8(body)
+        .map_err(|_| Error::Protocol("listing is not valid UTF-8".to_string()))?;
+    Ok(body.split_terminator("\r\n"))
+}
+
+/// Splits a scan listing such as "1 120" into the message number and the
+/// rest of the line.
+fn parse_pair(line: &str) -> Result<(u64, &str)> {
+    let mut parts = line.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some(id), Some(value)) => Ok((parse_number(id)?, value)),
+        _ => Err(Error::Protocol(format!("malformed listing: {}", line))),
+    }
+}
+
+fn parse_number(value: &str) -> Result<u64> {
+    value
+        .parse()
+        .map_err(|_| Error::Protocol(format!("expected a number: {}", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, DuplexStream};
+
+    /// Plays the server side of a session: sends the greeting, then checks
+    /// each command the client sends and answers it.
+    async fn serve(stream: DuplexStream, greeting: &str, script: &[(&str, &str)]) {
+        let mut stream = BufReader::new(stream);
+        stream.write_all(greeting.as_bytes()).await.unwrap();
+        for (expected, response) in script {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, format!("{}\r\n", expected));
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            serve(
+                server,
+                "+OK POP3 server ready\r\n",
+                &[
+                    ("USER alice", "+OK\r\n"),
+                    ("PASS secret", "+OK ready\r\n"),
+                    ("STAT", "+OK 2 320\r\n"),
+                    ("LIST", "+OK 2 messages\r\n1 120\r\n2 200\r\n.\r\n"),
+                    ("UIDL 2", "+OK 2 QhdPYR:00WBw1Ph7x7\r\n"),
+                    (
+                        "RETR 1",
+                        "+OK 120 octets\r\nSubject: hi\r\n\r\n..dot\r\n.\r\n",
+                    ),
+                    ("DELE 1", "+OK message 1 deleted\r\n"),
+                    ("QUIT", "+OK Bye!\r\n"),
+                ],
+            )
+            .await
+        });
+
+        let mut client = Client::from_stream(client).await.unwrap();
+        assert_eq!(client.greeting(), "POP3 server ready");
+        client.login("alice", "secret").await.unwrap();
+        assert_eq!(client.stat().await.unwrap(), (2, 320));
+        assert_eq!(client.list().await.unwrap(), vec![(1, 120), (2, 200)]);
+        assert_eq!(client.uidl_one(2).await.unwrap(), "QhdPYR:00WBw1Ph7x7");
+        assert_eq!(
+            client.retr(1).await.unwrap(),
+            b"Subject: hi\r\n\r\n.dot\r\n",
+            "dot-stuffing should be removed"
+        );
+        client.dele(1).await.unwrap();
+        client.quit().await.unwrap();
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_apop_and_error_codes() {
+        // The example exchange from RFC 1939 section 7.
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            serve(
+                server,
+                "+OK POP3 server ready <1896.697170952@dbc.mtview.ca.us>\r\n",
+                &[
+                    (
+                        "APOP mrose c4c9334bac560ecc979e58001b3e22fb",
+                        "-ERR [IN-USE] Mailbox already in use\r\n",
+                    ),
+                    ("STAT", "-ERR Session not in Transaction state\r\n"),
+                ],
+            )
+            .await
+        });
+
+        let mut client = Client::from_stream(client).await.unwrap();
+        match client.apop("mrose", "tanstaaf").await {
+            Err(Error::Server { code, message }) => {
+                assert_eq!(code, Some(ResponseCode::InUse));
+                assert_eq!(message, "Mailbox already in use");
+            }
+            other => panic!("expected an IN-USE error, got {:?}", other),
+        }
+        assert!(matches!(
+            client.stat().await,
+            Err(Error::Server { code: None, .. })
+        ));
+        server.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_non_utf8_listing_is_an_error() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = BufReader::new(server);
+            stream
+                .write_all(b"+OK POP3 server ready\r\n")
+                .await
+                .unwrap();
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "UIDL\r\n");
+            stream.write_all(b"+OK\r\n1 \xff\r\n.\r\n").await.unwrap();
+        });
+        let mut client = Client::from_stream(client).await.unwrap();
+        assert!(matches!(client.uidl().await, Err(Error::Protocol(_))));
+        server.await.unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stalled_server_times_out() {
+        let (client, mut server) = tokio::io::duplex(4096);
+        server
+            .write_all(b"+OK POP3 server ready\r\n")
+            .await
+            .unwrap();
+        let mut client = Client::from_stream(client).await.unwrap();
+        client.set_timeout(Duration::from_secs(5));
+        let start = tokio::time::Instant::now();
+        // The server reads the command but never answers it.
+        match client.noop().await {
+            Err(Error::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::TimedOut),
+            other => panic!("expected a timeout, got {:?}", other),
+        }
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        drop(server);
+    }
+
+    #[tokio::test]
+    async fn test_overlong_line_is_an_error() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let mut stream = BufReader::new(server);
+            stream
+                .write_all(b"+OK POP3 server ready\r\n")
+                .await
+                .unwrap();
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, "RETR 1\r\n");
+            let body = format!("+OK\r\n{}\r\n.\r\n", "X".repeat(MAX_LINE));
+            // The client stops reading once the line is over the limit.
+            let _ = stream.write_all(body.as_bytes()).await;
+        });
+        let mut client = Client::from_stream(client).await.unwrap();
+        assert!(matches!(client.retr(1).await, Err(Error::Protocol(_))));
+        drop(client);
+        server.await.unwrap();
+    }
+}
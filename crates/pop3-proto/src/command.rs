@@ -0,0 +1,225 @@
+use crate::StatusIndicator;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Apop(String, String),
+    Lang(Option<String>),
+    Auth(String, Option<String>),
+    Capa,
+    Noop,
+    Pass(String),
+    Quit,
+    User(String),
+    List(Option<u64>),
+    Retr(u64),
+    Dele(u64),
+    Rset,
+    Stat,
+    Stls,
+    Top(u64, u64),
+    Uidl(Option<u64>),
+    Utf8,
+}
+
+/// The command as sent by a client, without the trailing CRLF.
+impl std::fmt::Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Command::Apop(username, digest) => write!(f, "APOP {} {}", username, digest),
+            Command::Lang(Some(tag)) => write!(f, "LANG {}", tag),
+            Command::Lang(None) => write!(f, "LANG"),
+            Command::Auth(mechanism, Some(initial)) => write!(f, "AUTH {} {}", mechanism, initial),
+            Command::Auth(mechanism, None) => write!(f, "AUTH {}", mechanism),
+            Command::Capa => write!(f, "CAPA"),
+            Command::Noop => write!(f, "NOOP"),
+            Command::Pass(password) => write!(f, "PASS {}", password),
+            Command::Quit => write!(f, "QUIT"),
+            Command::User(username) => write!(f, "USER {}", username),
+            Command::List(Some(id)) => write!(f, "LIST {}", id),
+            Command::List(None) => write!(f, "LIST"),
+            Command::Retr(id) => write!(f, "RETR {}", id),
+            Command::Dele(id) => write!(f, "DELE {}", id),
+            Command::Rset => write!(f, "RSET"),
+            Command::Stat => write!(f, "STAT"),
+            Command::Stls => write!(f, "STLS"),
+            Command::Top(id, lines) => write!(f, "TOP {} {}", id, lines),
+            Command::Uidl(Some(id)) => write!(f, "UIDL {}", id),
+            Command::Uidl(None) => write!(f, "UIDL"),
+            Command::Utf8 => write!(f, "UTF8"),
+        }
+    }
+}
+
+impl Command {
+    pub fn parse(input: &str) -> Result<Command, StatusIndicator> {
+        let parts: Vec<&str> = input.split_whitespace().collect();
+        // Command keywords are ASCII; arguments are passed through untouched
+        // so UTF-8 usernames and passwords survive (RFC 6856).
+        let keyword = parts.first().map(|s| s.to_ascii_uppercase());
+        if let Some(max) = keyword.as_deref().and_then(max_args)
+            && parts.len() - 1 > max
+        {
+            return Err(StatusIndicator::Err(format!(
+                "Too many arguments for {}",
+                keyword.unwrap()
+            )));
+        }
+        match keyword.as_deref() {
+            Some("USER") => match parts.get(1) {
+                Some(username) => Ok(Command::User(username.to_string())),
+                None => Err(StatusIndicator::Err("USER requires username".to_string())),
+            },
+            // RFC 1939 section 7: PASS has a single argument, so spaces in
+            // it are part of the password. Only the whitespace separating it
+            // from the keyword is dropped.
+            Some("PASS") => match input
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .map(|(_, password)| password.trim_start())
+            {
+                Some(password) if !password.is_empty() => Ok(Command::Pass(password.to_string())),
+                _ => Err(StatusIndicator::Err("PASS requires password".to_string())),
+            },
+            Some("RETR") => match parts.get(1) {
+                Some(message_id) => match message_id.parse::<u64>() {
+                    Ok(id) => Ok(Command::Retr(id)),
+                    Err(e) => Err(StatusIndicator::Err(
+                        format!("error parsing ID: {}", e).to_string(),
+                    )),
+                },
+                None => Err(StatusIndicator::Err("RETR requires mail id".to_string())),
+            },
+            Some("DELE") => match parts.get(1) {
+                Some(message_id) => match message_id.parse::<u64>() {
+                    Ok(id) => Ok(Command::Dele(id)),
+                    Err(e) => Err(StatusIndicator::Err(
+                        format!("error parsing ID: {}", e).to_string(),
+                    )),
+                },
+                None => Err(StatusIndicator::Err("DELE requires mail id".to_string())),
+            },
+            Some("UIDL") => match parts.get(1) {
+                Some(message_id) => match message_id.parse::<u64>() {
+                    Ok(id) => Ok(Command::Uidl(Some(id))),
+                    Err(e) => Err(StatusIndicator::Err(
+                        format!("error parsing ID: {}", e).to_string(),
+                    )),
+                },
+                None => Ok(Command::Uidl(None)),
+            },
+            Some("TOP") => match (parts.get(1), parts.get(2)) {
+                (Some(message_id), Some(lines)) => {
+                    match (message_id.parse::<u64>(), lines.parse::<u64>()) {
+                        (Ok(id), Ok(n)) => Ok(Command::Top(id, n)),
+                        (Err(e), _) | (_, Err(e)) => Err(StatusIndicator::Err(format!(
+                            "error parsing TOP arguments: {}",
+                            e
+                        ))),
+                    }
+                }
+                _ => Err(StatusIndicator::Err(
+                    "TOP requires mail id and line count".to_string(),
+                )),
+            },
+            Some("RSET") => Ok(Command::Rset),
+            Some("STAT") => Ok(Command::Stat),
+            Some("STLS") => Ok(Command::Stls),
+            Some("APOP") => match (parts.get(1), parts.get(2)) {
+                (Some(username), Some(digest)) => {
+                    Ok(Command::Apop(username.to_string(), digest.to_string()))
+                }
+                _ => Err(StatusIndicator::Err(
+                    "APOP requires username and digest".to_string(),
+                )),
+            },
+            Some("AUTH") => match parts.get(1) {
+                Some(mechanism) => Ok(Command::Auth(
+                    mechanism.to_string(),
+                    parts.get(2).map(|s| s.to_string()),
+                )),
+                None => Err(StatusIndicator::Err("AUTH requires mechanism".to_string())),
+            },
+            Some("CAPA") => Ok(Command::Capa),
+            Some("LANG") => Ok(Command::Lang(parts.get(1).map(|s| s.to_string()))),
+            Some("UTF8") => Ok(Command::Utf8),
+            Some("NOOP") => Ok(Command::Noop),
+            Some("LIST") => match parts.get(1) {
+                Some(message_id) => match message_id.parse::<u64>() {
+                    Ok(id) => Ok(Command::List(Some(id))),
+                    Err(e) => Err(StatusIndicator::Err(
+                        format!("error parsing ID: {}", e).to_string(),
+                    )),
+                },
+                None => Ok(Command::List(None)),
+            },
+            Some("QUIT") => Ok(Command::Quit),
+            _ => Err(StatusIndicator::Err("Unknown command".to_string())),
+        }
+    }
+}
+
+/// The most arguments each command accepts, or `None` for PASS, whose
+/// argument may contain spaces.
+fn max_args(keyword: &str) -> Option<usize> {
+    match keyword {
+        "PASS" => None,
+        "TOP" | "APOP" | "AUTH" => Some(2),
+        "USER" | "RETR" | "DELE" | "UIDL" | "LANG" | "LIST" => Some(1),
+        _ => Some(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_argument_counts() {
+        assert!(matches!(Command::parse("stat"), Ok(Command::Stat)));
+        assert!(
+            Command::parse("STAT 1").is_err(),
+            "extra arguments should be rejected"
+        );
+        assert!(Command::parse("USER alice bob").is_err());
+        assert!(Command::parse("TOP 1 2 3").is_err());
+        assert!(matches!(
+            Command::parse("LIST 3"),
+            Ok(Command::List(Some(3)))
+        ));
+        assert!(
+            Command::parse("FOO").is_err(),
+            "unknown commands should be rejected"
+        );
+        assert!(Command::parse("").is_err());
+
+        for cmd in [
+            Command::Top(1, 10),
+            Command::Auth("PLAIN".to_string(), Some("=".to_string())),
+            Command::Uidl(None),
+            Command::Apop(
+                "alice".to_string(),
+                "c4c9334bac560ecc979e58001b3e22fb".to_string(),
+            ),
+        ] {
+            assert_eq!(
+                Command::parse(&cmd.to_string()).ok(),
+                Some(cmd),
+                "a formatted command should parse back to itself"
+            );
+        }
+
+        match Command::parse("PASS correct horse battery") {
+            Ok(Command::Pass(password)) => assert_eq!(password, "correct horse battery"),
+            _ => panic!("a password may contain spaces"),
+        }
+        assert!(Command::parse("PASS").is_err());
+        assert!(matches!(
+            Command::parse("PASS\tsecret"),
+            Ok(Command::Pass(password)) if password == "secret"
+        ));
+        assert!(matches!(
+            Command::parse("PASS   two  spaces"),
+            Ok(Command::Pass(password)) if password == "two  spaces"
+        ));
+    }
+}
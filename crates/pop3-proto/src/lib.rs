@@ -0,0 +1,12 @@
+//! The POP3 protocol without I/O: command parsing, response encoding and a
+//! session state machine that tells its host what to do next.
+
+mod command;
+mod response;
+mod session;
+
+pub use command::Command;
+pub use response::{MessageEncoder, MultiLine, ResponseCode, StatusIndicator};
+pub use session::{
+    Action, CredentialCheck, MailboxError, MessageInfo, SessionMachine, SessionState,
+};
@@ -0,0 +1,348 @@
+/// Extended response codes sent in brackets after `-ERR` (RFC 2449 section 8,
+/// RFC 3206).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseCode {
+    /// The mailbox is locked by another session.
+    InUse,
+    /// The user logged in too soon after their last login.
+    LoginDelay,
+    /// A temporary server-side failure; retrying later may succeed.
+    SysTemp,
+    /// A permanent server-side failure that retrying will not fix.
+    SysPerm,
+    /// The credentials were rejected.
+    Auth,
+}
+
+impl ResponseCode {
+    /// Parses the code inside the brackets of an `-ERR` response.
+    pub fn parse(code: &str) -> Option<Self> {
+        match code.to_ascii_uppercase().as_str() {
+            "IN-USE" => Some(ResponseCode::InUse),
+            "LOGIN-DELAY" => Some(ResponseCode::LoginDelay),
+            "SYS/TEMP" => Some(ResponseCode::SysTemp),
+            "SYS/PERM" => Some(ResponseCode::SysPerm),
+            "AUTH" => Some(ResponseCode::Auth),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ResponseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            ResponseCode::InUse => "IN-USE",
+            ResponseCode::LoginDelay => "LOGIN-DELAY",
+            ResponseCode::SysTemp => "SYS/TEMP",
+            ResponseCode::SysPerm => "SYS/PERM",
+            ResponseCode::Auth => "AUTH",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StatusIndicator {
+    Ok(String),
+    Err(String),
+    /// An error carrying an extended response code.
+    ErrCode(ResponseCode, String),
+    /// A SASL continuation line carrying a base64 encoded challenge.
+    Continue(String),
+    /// A positive response followed by a multi-line body.
+    MultiLine(MultiLine),
+}
+
+impl StatusIndicator {
+    /// Encodes the response exactly as it is sent to the client.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            StatusIndicator::MultiLine(multi_line) => multi_line.to_bytes(),
+            resp => resp.to_string().into_bytes(),
+        }
+    }
+}
+
+impl StatusIndicator {
+    /// Parses a single response line, without its CRLF, as a client reads
+    /// it. Response codes this crate does not know are left in the text.
+    /// A multi-line body is read by the caller after the `+OK` line.
+    pub fn parse(line: &str) -> Option<Self> {
+        if let Some(rest) = line.strip_prefix("+OK") {
+            return Some(StatusIndicator::Ok(rest.trim_start().to_string()));
+        }
+        if let Some(rest) = line.strip_prefix("-ERR") {
+            let rest = rest.trim_start();
+            if let Some((code, msg)) = rest.strip_prefix('[').and_then(|rest| rest.split_once(']'))
+                && let Some(code) = ResponseCode::parse(code)
+            {
+                return Some(StatusIndicator::ErrCode(code, msg.trim_start().to_string()));
+            }
+            return Some(StatusIndicator::Err(rest.to_string()));
+        }
+        if let Some(rest) = line.strip_prefix('+') {
+            return Some(StatusIndicator::Continue(rest.trim_start().to_string()));
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for StatusIndicator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StatusIndicator::Ok(msg) => write!(f, "+OK {}\r\n", msg),
+            StatusIndicator::Err(msg) => write!(f, "-ERR {}\r\n", msg),
+            StatusIndicator::ErrCode(code, msg) => write!(f, "-ERR [{}] {}\r\n", code, msg),
+            StatusIndicator::Continue(msg) => write!(f, "+ {}\r\n", msg),
+            StatusIndicator::MultiLine(multi_line) => {
+                write!(f, "{}", String::from_utf8_lossy(&multi_line.to_bytes()))
+            }
+        }
+    }
+}
+
+/// A multi-line response (RFC 1939 section 3). Every line is CRLF
+/// terminated, lines starting with "." are byte-stuffed, and the response is
+/// ended by a line holding only ".".
+#[derive(Debug, PartialEq, Eq)]
+pub struct MultiLine {
+    status: String,
+    body: Vec<u8>,
+}
+
+impl MultiLine {
+    pub fn new(status: &str) -> Self {
+        Self {
+            status: status.to_string(),
+            body: Vec::new(),
+        }
+    }
+
+    /// Appends one line, given without its line terminator.
+    pub fn push_line(&mut self, line: &[u8]) {
+        if line.first() == Some(&b'.') {
+            self.body.push(b'.');
+        }
+        self.body.extend_from_slice(line);
+        self.body.extend_from_slice(b"\r\n");
+    }
+
+    /// Encodes the status line, body and terminating ".".
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = format!("+OK {}\r\n", self.status).into_bytes();
+        bytes.extend_from_slice(&self.body);
+        bytes.extend_from_slice(b".\r\n");
+        bytes
+    }
+}
+
+/// Encodes a message into a multi-line body incrementally, so it can be sent
+/// in chunks of any size. Bare LF line endings become CRLF, lines starting
+/// with "." are byte-stuffed and an unterminated final line is terminated,
+/// so the body always takes `maildir::canonical_size` octets before
+/// stuffing. Other bytes are passed through unchanged.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MessageEncoder {
+    at_line_start: bool,
+    pending_cr: bool,
+    in_body: bool,
+    /// Body lines still to send for TOP; `None` sends the whole message.
+    lines_left: Option<u64>,
+}
+
+impl MessageEncoder {
+    /// Encodes the whole message, for RETR.
+    pub fn new() -> Self {
+        Self {
+            at_line_start: true,
+            pending_cr: false,
+            in_body: false,
+            lines_left: None,
+        }
+    }
+
+    /// Encodes the header block, the blank separator line and the first
+    /// `lines` lines of the body, for TOP.
+    pub fn top(lines: u64) -> Self {
+        Self {
+            lines_left: Some(lines),
+            ..Self::new()
+        }
+    }
+
+    /// Whether everything to be sent has been encoded, so the rest of the
+    /// message need not be read.
+    pub fn is_done(&self) -> bool {
+        self.in_body && self.lines_left == Some(0)
+    }
+
+    /// Encodes the next chunk of the message into `out`.
+    pub fn encode(&mut self, chunk: &[u8], out: &mut Vec<u8>) {
+        for &b in chunk {
+            if self.is_done() {
+                return;
+            }
+            if b == b'\n' {
+                // A CR directly before the LF belongs to the line ending.
+                self.pending_cr = false;
+                self.end_line(out);
+                continue;
+            }
+            if std::mem::take(&mut self.pending_cr) {
+                self.push_byte(b'\r', out);
+            }
+            if b == b'\r' {
+                self.pending_cr = true;
+            } else {
+                self.push_byte(b, out);
+            }
+        }
+    }
+
+    /// Terminates a final line that lacks a line ending. The closing "."
+    /// line is left to the caller.
+    pub fn finish(mut self, out: &mut Vec<u8>) {
+        if self.is_done() {
+            return;
+        }
+        if std::mem::take(&mut self.pending_cr) {
+            self.push_byte(b'\r', out);
+        }
+        if !self.at_line_start {
+            out.extend_from_slice(b"\r\n");
+        }
+    }
+
+    fn push_byte(&mut self, b: u8, out: &mut Vec<u8>) {
+        if self.at_line_start && b == b'.' {
+            out.push(b'.');
+        }
+        self.at_line_start = false;
+        out.push(b);
+    }
+
+    fn end_line(&mut self, out: &mut Vec<u8>) {
+        out.extend_from_slice(b"\r\n");
+        if self.in_body {
+            if let Some(lines_left) = &mut self.lines_left {
+                *lines_left -= 1;
+            }
+        } else if self.at_line_start {
+            self.in_body = true;
+        }
+        self.at_line_start = true;
+    }
+}
+
+impl Default for MessageEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status_line() {
+        assert_eq!(
+            StatusIndicator::parse("+OK 2 320"),
+            Some(StatusIndicator::Ok("2 320".to_string()))
+        );
+        assert_eq!(
+            StatusIndicator::parse("-ERR [SYS/TEMP] try later"),
+            Some(StatusIndicator::ErrCode(
+                ResponseCode::SysTemp,
+                "try later".to_string()
+            ))
+        );
+        assert_eq!(
+            StatusIndicator::parse("-ERR [UTF8] not supported"),
+            Some(StatusIndicator::Err("[UTF8] not supported".to_string())),
+            "unknown codes should stay in the text"
+        );
+        assert_eq!(
+            StatusIndicator::parse("+ "),
+            Some(StatusIndicator::Continue(String::new()))
+        );
+        assert_eq!(StatusIndicator::parse("* OK"), None);
+    }
+
+    /// Encodes `msg` through `encoder`, feeding it in chunks of `chunk_size`.
+    fn encode(msg: &[u8], mut encoder: MessageEncoder, chunk_size: usize) -> Vec<u8> {
+        let mut out = Vec::new();
+        for chunk in msg.chunks(chunk_size.max(1)) {
+            encoder.encode(chunk, &mut out);
+        }
+        encoder.finish(&mut out);
+        out
+    }
+
+    #[test]
+    fn test_top_of_message() {
+        let msg = b"Subject: hi\nFrom: a@b\n\nfirst\n.second\nthird\n";
+
+        assert_eq!(
+            encode(msg, MessageEncoder::top(0), msg.len()),
+            b"Subject: hi\r\nFrom: a@b\r\n\r\n",
+            "TOP 0 should return only the headers and separator"
+        );
+        assert_eq!(
+            encode(msg, MessageEncoder::top(2), msg.len()),
+            b"Subject: hi\r\nFrom: a@b\r\n\r\nfirst\r\n..second\r\n",
+            "body lines starting with '.' should be dot-stuffed"
+        );
+        assert_eq!(
+            encode(msg, MessageEncoder::top(10), msg.len()),
+            b"Subject: hi\r\nFrom: a@b\r\n\r\nfirst\r\n..second\r\nthird\r\n",
+            "asking for more lines than exist should return the whole message"
+        );
+    }
+
+    #[test]
+    fn test_message_encoding() {
+        let msgs: [&[u8]; 6] = [
+            b"Subject: hi\n\nbody\n",
+            b"Subject: hi\r\n\r\nmixed\nendings\r\n",
+            b"Subject: hi\n\n.\n..\nno final newline",
+            b"",
+            b"Subject: \xff 8-bit\n\nbody\r",
+            b"Subject: hi\n\nbare\rcr\r\n.\r\n",
+        ];
+        for msg in msgs {
+            let body = encode(msg, MessageEncoder::new(), msg.len());
+            for chunk_size in 1..msg.len().max(1) {
+                assert_eq!(
+                    encode(msg, MessageEncoder::new(), chunk_size),
+                    body,
+                    "chunk boundaries should not change the encoding"
+                );
+            }
+
+            let unstuffed: Vec<u8> = body
+                .split(|&b| b == b'\n')
+                .map(|line| line.strip_prefix(b".").unwrap_or(line))
+                .collect::<Vec<_>>()
+                .join(&b'\n');
+            assert_eq!(
+                unstuffed.len() as u64,
+                maildir::canonical_size(msg),
+                "the unstuffed body should match the size reported by LIST"
+            );
+            assert!(body.is_empty() || body.ends_with(b"\r\n"));
+            for (i, &b) in body.iter().enumerate() {
+                if b == b'\n' {
+                    assert_eq!(body[i - 1], b'\r', "every LF should be part of a CRLF");
+                }
+            }
+        }
+
+        assert_eq!(encode(b".\n", MessageEncoder::new(), 1), b"..\r\n");
+        assert_eq!(
+            encode(b"a\r\r\nb", MessageEncoder::new(), 1),
+            b"a\r\r\nb\r\n",
+            "only the CR directly before LF belongs to the line ending"
+        );
+    }
+}
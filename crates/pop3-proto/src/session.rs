@@ -0,0 +1,605 @@
+use std::collections::BTreeSet;
+
+use crate::{Command, MessageEncoder, MultiLine, ResponseCode, StatusIndicator};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionState {
+    Authorization,
+    AuthorizationWithUser(String),
+    Update(String),
+    Transaction(String),
+}
+
+const NOT_IN_AUTHORIZATION: &str = "Session not in Authorization state ";
+const NOT_IN_TRANSACTION: &str = "Session not in Transaction state ";
+
+/// What the host must do next. Actions that ask a question are answered by
+/// calling the matching method on `SessionMachine`, which returns the next
+/// action.
+#[derive(Debug)]
+pub enum Action {
+    /// Send this response.
+    Reply(StatusIndicator),
+    /// Check a USER/PASS login, then call `credentials_checked`.
+    CheckPassword { username: String, password: String },
+    /// Check an APOP digest against the greeting timestamp, then call
+    /// `credentials_checked`.
+    CheckApop { username: String, digest: String },
+    /// Run a SASL exchange for AUTH, then call `authenticated` on success.
+    /// The exchange itself, including unknown mechanisms, is up to the host.
+    Authenticate {
+        mechanism: String,
+        initial_response: Option<String>,
+    },
+    /// Lock and scan the user's maildrop, then call `mailbox_opened`.
+    OpenMailbox(String),
+    /// Send `+OK status`, then message `id` run through `encoder`, then the
+    /// terminating ".".
+    SendMessage {
+        id: u64,
+        status: String,
+        encoder: MessageEncoder,
+    },
+    /// Send the capabilities advertised in the current state.
+    ListCapabilities,
+    /// Send the languages the host supports.
+    ListLanguages,
+    /// Switch to the language with this tag, or the default for "*".
+    SetLanguage(String),
+    /// Send this response, then negotiate TLS.
+    StartTls(StatusIndicator),
+    /// Remove the `deleted` messages and mark the `seen` ones, those
+    /// retrieved with RETR, as seen. Then call `update_finished`.
+    Update { deleted: Vec<u64>, seen: Vec<u64> },
+    /// Send this response, then close the connection.
+    Close(StatusIndicator),
+}
+
+/// The host's answer to `Action::CheckPassword` or `Action::CheckApop`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CredentialCheck {
+    Valid,
+    Invalid,
+    /// The credential store could not be consulted.
+    Unavailable,
+}
+
+/// Why the host could not open a maildrop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MailboxError {
+    /// Another session holds the maildrop lock.
+    InUse,
+    Unavailable(String),
+}
+
+/// What the session needs to know about each message in the maildrop.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageInfo {
+    /// Size of the message with CRLF line endings.
+    pub size: u64,
+    pub uidl: String,
+}
+
+/// A login waiting on `Action::CheckPassword` or `Action::CheckApop`.
+enum PendingLogin {
+    Password(String),
+    Apop(String),
+}
+
+/// The POP3 session state machine. It performs no I/O: every command is
+/// turned into an `Action`, and anything that touches credentials or the
+/// maildrop is left to the host.
+pub struct SessionMachine {
+    state: SessionState,
+    tls_available: bool,
+    tls_active: bool,
+    /// Set by the UTF8 command; USER and PASS arguments are then normalized
+    /// with SASLprep.
+    utf8: bool,
+    pending_login: Option<PendingLogin>,
+    /// The maildrop, where message `n` is at index `n - 1`.
+    messages: Vec<MessageInfo>,
+    deleted: BTreeSet<u64>,
+    /// Messages sent with RETR, to be marked as seen on QUIT.
+    retrieved: BTreeSet<u64>,
+}
+
+impl SessionMachine {
+    pub fn new(tls_available: bool, tls_active: bool) -> Self {
+        Self {
+            state: SessionState::Authorization,
+            tls_available,
+            tls_active,
+            utf8: false,
+            pending_login: None,
+            messages: Vec::new(),
+            deleted: BTreeSet::new(),
+            retrieved: BTreeSet::new(),
+        }
+    }
+
+    pub fn state(&self) -> &SessionState {
+        &self.state
+    }
+
+    pub fn tls_active(&self) -> bool {
+        self.tls_active
+    }
+
+    fn in_authorization(&self) -> bool {
+        matches!(
+            self.state,
+            SessionState::Authorization | SessionState::AuthorizationWithUser(_)
+        )
+    }
+
+    /// Looks up a message for a command that takes a message number,
+    /// rejecting numbers that are deleted or do not exist.
+    fn message(&self, id: u64) -> Result<&MessageInfo, StatusIndicator> {
+        if self.deleted.contains(&id) {
+            return Err(StatusIndicator::Err(format!(
+                "message {} already deleted",
+                id
+            )));
+        }
+        id.checked_sub(1)
+            .and_then(|i| self.messages.get(i as usize))
+            .ok_or_else(|| StatusIndicator::Err("no such message".to_string()))
+    }
+
+    /// The undeleted messages and their numbers.
+    fn undeleted(&self) -> impl Iterator<Item = (u64, &MessageInfo)> {
+        (1..)
+            .zip(&self.messages)
+            .filter(|(id, _)| !self.deleted.contains(id))
+    }
+
+    /// Returns the message count and total size of the maildrop, skipping
+    /// deleted messages.
+    fn stat(&self) -> (u64, u64) {
+        self.undeleted()
+            .fold((0, 0), |(count, octets), (_, message)| {
+                (count + 1, octets + message.size)
+            })
+    }
+
+    fn in_transaction(&self) -> bool {
+        matches!(self.state, SessionState::Transaction(_))
+    }
+
+    /// Handles one command from the client.
+    pub fn handle(&mut self, cmd: Command) -> Action {
+        match cmd {
+            Command::Capa => match &self.state {
+                SessionState::Update(_) => {
+                    reply_err("Session not in Authorization or Transaction state")
+                }
+                _ => Action::ListCapabilities,
+            },
+            Command::Lang(None) => Action::ListLanguages,
+            Command::Lang(Some(tag)) => Action::SetLanguage(tag),
+            Command::Utf8 => {
+                if !self.in_authorization() {
+                    return reply_err(NOT_IN_AUTHORIZATION);
+                }
+                self.utf8 = true;
+                reply_ok("UTF8 enabled")
+            }
+            Command::User(username) => {
+                if !self.in_authorization() {
+                    return reply_err(NOT_IN_AUTHORIZATION);
+                }
+                let Some(username) = normalize_credential(&username, self.utf8) else {
+                    return reply_err("Invalid username");
+                };
+                self.state = SessionState::AuthorizationWithUser(username);
+                reply_ok("User accepted")
+            }
+            Command::Pass(password) => {
+                let SessionState::AuthorizationWithUser(username) = &self.state else {
+                    return reply_err("No username set - send USER first");
+                };
+                let username = username.clone();
+                let Some(password) = normalize_credential(&password, self.utf8) else {
+                    return reply_code(ResponseCode::Auth, "Username or password are incorrect");
+                };
+                self.pending_login = Some(PendingLogin::Password(username.clone()));
+                Action::CheckPassword { username, password }
+            }
+            Command::Apop(username, digest) => {
+                if !self.in_authorization() {
+                    return reply_err(NOT_IN_AUTHORIZATION);
+                }
+                self.pending_login = Some(PendingLogin::Apop(username.clone()));
+                Action::CheckApop { username, digest }
+            }
+            Command::Auth(mechanism, initial_response) => {
+                if !self.in_authorization() {
+                    return reply_err(NOT_IN_AUTHORIZATION);
+                }
+                Action::Authenticate {
+                    mechanism,
+                    initial_response,
+                }
+            }
+            Command::Stls => {
+                if !self.in_authorization() {
+                    return reply_err(NOT_IN_AUTHORIZATION);
+                }
+                if self.tls_active {
+                    return reply_err("Command not permitted when TLS active");
+                }
+                if !self.tls_available {
+                    return reply_err("TLS not available");
+                }
+                Action::StartTls(StatusIndicator::Ok("Begin TLS negotiation".to_string()))
+            }
+            Command::Quit => match &self.state {
+                SessionState::Transaction(username) => {
+                    self.state = SessionState::Update(username.clone());
+                    let seen: Vec<u64> =
+                        self.retrieved.difference(&self.deleted).copied().collect();
+                    if self.deleted.is_empty() && seen.is_empty() {
+                        return self.update_finished(0);
+                    }
+                    Action::Update {
+                        deleted: self.deleted.iter().copied().collect(),
+                        seen,
+                    }
+                }
+                _ => Action::Close(StatusIndicator::Ok("Bye!".to_string())),
+            },
+            _ if !self.in_transaction() => reply_err(NOT_IN_TRANSACTION),
+            Command::Retr(id) => self.send_message(id, None),
+            Command::Top(id, lines) => self.send_message(id, Some(lines)),
+            Command::Stat => {
+                let (count, octets) = self.stat();
+                reply_ok(&format!("{} {}", count, octets))
+            }
+            Command::List(Some(id)) => match self.message(id) {
+                Ok(message) => reply_ok(&format!("{} {}", id, message.size)),
+                Err(e) => Action::Reply(e),
+            },
+            Command::List(None) => {
+                let (count, octets) = self.stat();
+                let mut resp = MultiLine::new(&format!("{} messages ({} octets)", count, octets));
+                for (id, message) in self.undeleted() {
+                    resp.push_line(format!("{} {}", id, message.size).as_bytes());
+                }
+                Action::Reply(StatusIndicator::MultiLine(resp))
+            }
+            Command::Uidl(Some(id)) => match self.message(id) {
+                Ok(message) => reply_ok(&format!("{} {}", id, message.uidl)),
+                Err(e) => Action::Reply(e),
+            },
+            Command::Uidl(None) => {
+                let mut resp = MultiLine::new("unique-id listing follows");
+                for (id, message) in self.undeleted() {
+                    resp.push_line(format!("{} {}", id, message.uidl).as_bytes());
+                }
+                Action::Reply(StatusIndicator::MultiLine(resp))
+            }
+            Command::Dele(id) => {
+                if id == 0 || id > self.messages.len() as u64 {
+                    return reply_err("message does not exist");
+                }
+                if self.deleted.insert(id) {
+                    return reply_ok(&format!("message {} deleted", id));
+                }
+                reply_err(&format!("message {} already deleted", id))
+            }
+            Command::Rset => {
+                self.deleted.clear();
+                let (count, octets) = self.stat();
+                reply_ok(&format!("{} messages ({} octets)", count, octets))
+            }
+            Command::Noop => reply_ok("NOOP"),
+        }
+    }
+
+    /// Handles RETR and TOP, which hand the message itself to the host.
+    fn send_message(&mut self, id: u64, top: Option<u64>) -> Action {
+        let size = match self.message(id) {
+            Ok(message) => message.size,
+            Err(e) => return Action::Reply(e),
+        };
+        let (status, encoder) = match top {
+            Some(lines) => (
+                "top of message follows".to_string(),
+                MessageEncoder::top(lines),
+            ),
+            None => {
+                self.retrieved.insert(id);
+                (format!("{} octets", size), MessageEncoder::new())
+            }
+        };
+        Action::SendMessage {
+            id,
+            status,
+            encoder,
+        }
+    }
+
+    /// Continues after `Action::CheckPassword` or `Action::CheckApop`.
+    pub fn credentials_checked(&mut self, check: CredentialCheck) -> Action {
+        let Some(pending) = self.pending_login.take() else {
+            return reply_err("No authentication in progress");
+        };
+        let (username, rejected) = match pending {
+            PendingLogin::Password(username) => (username, "Username or password are incorrect"),
+            PendingLogin::Apop(username) => (username, "Username or digest are incorrect"),
+        };
+        match check {
+            CredentialCheck::Valid => self.authenticated(username),
+            CredentialCheck::Invalid => reply_code(ResponseCode::Auth, rejected),
+            CredentialCheck::Unavailable => {
+                reply_code(ResponseCode::SysTemp, "Unable to verify credentials")
+            }
+        }
+    }
+
+    /// Continues after the host has authenticated `username`, whether
+    /// through a login check or a SASL exchange.
+    pub fn authenticated(&mut self, username: String) -> Action {
+        if !self.in_authorization() {
+            return reply_err(NOT_IN_AUTHORIZATION);
+        }
+        Action::OpenMailbox(username)
+    }
+
+    /// Continues after `Action::OpenMailbox`, entering the Transaction state
+    /// if the maildrop could be locked and scanned.
+    pub fn mailbox_opened(
+        &mut self,
+        username: String,
+        mailbox: Result<Vec<MessageInfo>, MailboxError>,
+    ) -> Action {
+        match mailbox {
+            Ok(messages) => {
+                self.messages = messages;
+                self.deleted.clear();
+                self.retrieved.clear();
+                self.state = SessionState::Transaction(username);
+                reply_ok("Mailbox locked and ready")
+            }
+            Err(MailboxError::InUse) => reply_code(ResponseCode::InUse, "Mailbox already in use"),
+            Err(MailboxError::Unavailable(e)) => reply_code(
+                ResponseCode::SysTemp,
+                &format!("Failed to access mailbox: {}", e),
+            ),
+        }
+    }
+
+    /// Continues after `Action::Update`, given how many messages could not
+    /// be removed.
+    pub fn update_finished(&mut self, failed: usize) -> Action {
+        if failed > 0 {
+            return Action::Close(StatusIndicator::ErrCode(
+                ResponseCode::SysTemp,
+                "some deleted messages not removed".to_string(),
+            ));
+        }
+        Action::Close(StatusIndicator::Ok("Bye!".to_string()))
+    }
+}
+
+fn reply_ok(msg: &str) -> Action {
+    Action::Reply(StatusIndicator::Ok(msg.to_string()))
+}
+
+fn reply_err(msg: &str) -> Action {
+    Action::Reply(StatusIndicator::Err(msg.to_string()))
+}
+
+fn reply_code(code: ResponseCode, msg: &str) -> Action {
+    Action::Reply(StatusIndicator::ErrCode(code, msg.to_string()))
+}
+
+/// Prepares a USER or PASS argument for lookup. Once the client has issued
+/// UTF8, arguments are normalized with SASLprep (RFC 4013) as RFC 6856
+/// requires; `None` means the argument contains prohibited characters.
+fn normalize_credential(value: &str, utf8: bool) -> Option<String> {
+    if !utf8 {
+        return Some(value.to_string());
+    }
+    stringprep::saslprep(value).ok().map(|v| v.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn logged_in(messages: &[u64]) -> SessionMachine {
+        let mut session = SessionMachine::new(false, false);
+        session.handle(Command::User("alice".to_string()));
+        session.handle(Command::Pass("secret".to_string()));
+        session.credentials_checked(CredentialCheck::Valid);
+        let messages = messages
+            .iter()
+            .enumerate()
+            .map(|(i, &size)| MessageInfo {
+                size,
+                uidl: format!("uid{}", i + 1),
+            })
+            .collect();
+        session.mailbox_opened("alice".to_string(), Ok(messages));
+        session
+    }
+
+    fn reply(action: Action) -> String {
+        match action {
+            Action::Reply(resp) => resp.to_string(),
+            other => panic!("expected a reply, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_user_pass_login() {
+        let mut session = SessionMachine::new(false, false);
+        assert_eq!(
+            reply(session.handle(Command::Pass("secret".to_string()))),
+            "-ERR No username set - send USER first\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::Stat)),
+            "-ERR Session not in Transaction state \r\n",
+            "STAT should be refused before login"
+        );
+        session.handle(Command::User("alice".to_string()));
+        match session.handle(Command::Pass("secret".to_string())) {
+            Action::CheckPassword { username, password } => {
+                assert_eq!((username.as_str(), password.as_str()), ("alice", "secret"));
+            }
+            other => panic!("expected a password check, got {:?}", other),
+        }
+        assert_eq!(
+            reply(session.credentials_checked(CredentialCheck::Invalid)),
+            "-ERR [AUTH] Username or password are incorrect\r\n"
+        );
+        assert_eq!(
+            session.state(),
+            &SessionState::AuthorizationWithUser("alice".to_string())
+        );
+
+        session.handle(Command::Pass("secret".to_string()));
+        match session.credentials_checked(CredentialCheck::Valid) {
+            Action::OpenMailbox(username) => assert_eq!(username, "alice"),
+            other => panic!("expected the mailbox to be opened, got {:?}", other),
+        }
+        assert_eq!(
+            reply(session.mailbox_opened("alice".to_string(), Err(MailboxError::InUse))),
+            "-ERR [IN-USE] Mailbox already in use\r\n"
+        );
+        assert_eq!(
+            reply(session.mailbox_opened("alice".to_string(), Ok(Vec::new()))),
+            "+OK Mailbox locked and ready\r\n"
+        );
+        assert_eq!(
+            session.state(),
+            &SessionState::Transaction("alice".to_string())
+        );
+        assert_eq!(
+            reply(session.handle(Command::User("bob".to_string()))),
+            "-ERR Session not in Authorization state \r\n"
+        );
+    }
+
+    #[test]
+    fn test_transaction_and_update() {
+        let mut session = logged_in(&[100, 200, 300]);
+        assert_eq!(reply(session.handle(Command::Stat)), "+OK 3 600\r\n");
+        assert_eq!(
+            reply(session.handle(Command::Dele(2))),
+            "+OK message 2 deleted\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::Dele(2))),
+            "-ERR message 2 already deleted\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::Dele(4))),
+            "-ERR message does not exist\r\n"
+        );
+        assert_eq!(reply(session.handle(Command::Stat)), "+OK 2 400\r\n");
+        assert_eq!(
+            reply(session.handle(Command::List(None))),
+            "+OK 2 messages (400 octets)\r\n1 100\r\n3 300\r\n.\r\n",
+            "deleted messages should be left out of listings"
+        );
+        assert_eq!(
+            reply(session.handle(Command::List(Some(3)))),
+            "+OK 3 300\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::List(Some(2)))),
+            "-ERR message 2 already deleted\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::List(Some(4)))),
+            "-ERR no such message\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::Uidl(Some(3)))),
+            "+OK 3 uid3\r\n"
+        );
+        assert_eq!(
+            reply(session.handle(Command::Retr(2))),
+            "-ERR message 2 already deleted\r\n"
+        );
+        match session.handle(Command::Retr(3)) {
+            Action::SendMessage { id, status, .. } => {
+                assert_eq!((id, status.as_str()), (3, "300 octets"));
+            }
+            other => panic!("expected the message to be sent, got {:?}", other),
+        }
+
+        assert_eq!(
+            reply(session.handle(Command::Rset)),
+            "+OK 3 messages (600 octets)\r\n"
+        );
+        session.handle(Command::Dele(1));
+        session.handle(Command::Dele(3));
+        session.handle(Command::Top(2, 0));
+        match session.handle(Command::Quit) {
+            Action::Update { deleted, seen } => {
+                assert_eq!(deleted, vec![1, 3]);
+                assert!(
+                    seen.is_empty(),
+                    "neither a deleted message nor one read with TOP is marked seen"
+                );
+            }
+            other => panic!("QUIT should ask for deletions, got {:?}", other),
+        }
+        assert_eq!(session.state(), &SessionState::Update("alice".to_string()));
+        match session.update_finished(1) {
+            Action::Close(resp) => assert_eq!(
+                resp.to_string(),
+                "-ERR [SYS/TEMP] some deleted messages not removed\r\n"
+            ),
+            other => panic!("expected the session to close, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_retrieved_messages_marked_seen() {
+        let mut session = logged_in(&[100, 200, 300]);
+        session.handle(Command::Retr(3));
+        session.handle(Command::Retr(1));
+        session.handle(Command::Retr(1));
+        session.handle(Command::Rset);
+        match session.handle(Command::Quit) {
+            Action::Update { deleted, seen } => {
+                assert!(deleted.is_empty());
+                assert_eq!(seen, vec![1, 3], "RSET only undoes deletions");
+            }
+            other => panic!("QUIT should mark retrieved messages, got {:?}", other),
+        }
+
+        let mut session = logged_in(&[100]);
+        assert!(matches!(
+            session.handle(Command::Quit),
+            Action::Close(StatusIndicator::Ok(_))
+        ));
+    }
+
+    #[test]
+    fn test_quit_and_stls_outside_transaction() {
+        let mut session = SessionMachine::new(true, false);
+        assert!(matches!(
+            session.handle(Command::Stls),
+            Action::StartTls(StatusIndicator::Ok(_))
+        ));
+        let mut session = SessionMachine::new(true, true);
+        assert_eq!(
+            reply(session.handle(Command::Stls)),
+            "-ERR Command not permitted when TLS active\r\n"
+        );
+        assert!(
+            matches!(
+                session.handle(Command::Quit),
+                Action::Close(StatusIndicator::Ok(_))
+            ),
+            "QUIT before login should close without entering Update"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use pop3_proto::SessionState;
+
+/// The session states in which a capability is advertised.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Availability {
+    Always,
+    Authorization,
+    Transaction,
+}
+
+/// Whether a capability depends on the connection being encrypted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TlsRequirement {
+    Any,
+    PlaintextOnly,
+    TlsOnly,
+}
+
+/// A single RFC 2449 capability line, e.g. `SASL PLAIN LOGIN`.
+#[derive(Debug, Clone)]
+pub struct Capability {
+    name: String,
+    args: Vec<String>,
+    availability: Availability,
+    tls: TlsRequirement,
+}
+
+impl Capability {
+    pub fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            args: Vec::new(),
+            availability: Availability::Always,
+            tls: TlsRequirement::Any,
+        }
+    }
+
+    pub fn with_args(mut self, args: &[&str]) -> Self {
+        self.args = args.iter().map(|a| a.to_string()).collect();
+        self
+    }
+
+    pub fn available_in(mut self, availability: Availability) -> Self {
+        self.availability = availability;
+        self
+    }
+
+    pub fn requires(mut self, tls: TlsRequirement) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    fn is_available(&self, state: &SessionState, tls_active: bool) -> bool {
+        let state_ok = match self.availability {
+            Availability::Always => true,
+            Availability::Authorization => matches!(
+                state,
+                SessionState::Authorization | SessionState::AuthorizationWithUser(_)
+            ),
+            Availability::Transaction => matches!(state, SessionState::Transaction(_)),
+        };
+        let tls_ok = match self.tls {
+            TlsRequirement::Any => true,
+            TlsRequirement::PlaintextOnly => !tls_active,
+            TlsRequirement::TlsOnly => tls_active,
+        };
+        state_ok && tls_ok
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        Ok(())
+    }
+}
+
+/// The set of optional features this server advertises through CAPA.
+#[derive(Debug, Default)]
+pub struct CapabilityRegistry {
+    capabilities: Vec<Capability>,
+}
+
+impl CapabilityRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a capability, replacing any earlier one with the same name
+    /// and TLS requirement. A capability whose arguments depend on TLS can be
+    /// registered once per requirement.
+    pub fn register(&mut self, capability: Capability) {
+        self.capabilities
+            .retain(|c| c.name != capability.name || c.tls != capability.tls);
+        self.capabilities.push(capability);
+    }
+
+    /// Returns the capability lines to advertise for the given session.
+    pub fn list(&self, state: &SessionState, tls_active: bool) -> Vec<String> {
+        self.capabilities
+            .iter()
+            .filter(|c| c.is_available(state, tls_active))
+            .map(|c| c.to_string())
+            .collect()
+    }
+}
+
+/// Builds the registry of capabilities implemented by this server.
+pub fn default_registry() -> CapabilityRegistry {
+    let mut registry = CapabilityRegistry::new();
+    registry.register(Capability::new("TOP"));
+    registry.register(Capability::new("UIDL"));
+    registry.register(Capability::new("USER").available_in(Availability::Authorization));
+    registry.register(Capability::new("PIPELINING"));
+    registry.register(Capability::new("RESP-CODES"));
+    registry.register(Capability::new("AUTH-RESP-CODE"));
+    registry.register(
+        Capability::new("UTF8")
+            .with_args(&["USER"])
+            .available_in(Availability::Authorization),
+    );
+    registry.register(Capability::new("LANG"));
+    registry.register(Capability::new("EXPIRE").with_args(&["NEVER"]));
+    // RFC 2449 section 6.5: no delay is enforced between logins.
+    registry.register(Capability::new("LOGIN-DELAY").with_args(&["0"]));
+    registry.register(
+        Capability::new("IMPLEMENTATION")
+            .with_args(&[concat!("pop3-server-", env!("CARGO_PKG_VERSION"))]),
+    );
+    registry
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_filters_by_state_and_tls() {
+        let mut registry = CapabilityRegistry::new();
+        registry.register(Capability::new("TOP"));
+        registry.register(
+            Capability::new("STLS")
+                .available_in(Availability::Authorization)
+                .requires(TlsRequirement::PlaintextOnly),
+        );
+        registry.register(Capability::new("SASL").with_args(&["PLAIN", "LOGIN"]));
+
+        assert_eq!(
+            registry.list(&SessionState::Authorization, false),
+            vec!["TOP", "STLS", "SASL PLAIN LOGIN"]
+        );
+        assert_eq!(
+            registry.list(&SessionState::Authorization, true),
+            vec!["TOP", "SASL PLAIN LOGIN"],
+            "STLS should not be offered once TLS is active"
+        );
+        assert_eq!(
+            registry.list(&SessionState::Transaction("user".to_string()), false),
+            vec!["TOP", "SASL PLAIN LOGIN"],
+            "STLS should only be offered in the Authorization state"
+        );
+
+        registry.register(Capability::new("SASL").with_args(&["PLAIN"]));
+        assert_eq!(
+            registry.list(&SessionState::Authorization, true),
+            vec!["TOP", "SASL PLAIN"],
+            "re-registering a capability should replace it"
+        );
+    }
+}
@@ -0,0 +1,399 @@
+//! Pulls mail from remote POP3 accounts into local Maildirs.
+//!
+//! Accounts and the UIDLs already fetched are kept in their own sled
+//! database, so the fetcher can run alongside the server.
+
+use std::{collections::HashSet, io, sync::Arc, time::Duration};
+
+use maildir::MailDir;
+use pop3_client::Client;
+use rustls::{ClientConfig, pki_types::ServerName};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    task::JoinSet,
+};
+
+use crate::IOResult;
+
+/// The sled database holding fetch accounts and seen UIDLs.
+pub const FETCH_DB: &str = "fetch_db";
+
+/// How often accounts are polled unless POP3_FETCH_INTERVAL says otherwise.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long one account's poll may take in all. Connects and single reads
+/// and writes are bounded by `pop3_client::DEFAULT_TIMEOUT`; this also
+/// stops a server that answers slowly but steadily from holding up the next
+/// poll. Messages delivered before the deadline are not fetched again.
+pub const ACCOUNT_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Tree mapping an account key to its JSON-encoded `FetchAccount`.
+const ACCOUNTS_TREE: &str = "fetch_accounts";
+
+/// Tree holding one `<account key>\0<uidl>` entry per message fetched.
+const SEEN_TREE: &str = "fetch_seen";
+
+/// How the connection to the remote server is secured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Security {
+    Plain,
+    /// POP3S: TLS from the start of the connection.
+    Tls,
+    /// Upgrade a plaintext connection with STLS before logging in.
+    Stls,
+}
+
+/// A remote mailbox whose mail is delivered to a local user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FetchAccount {
+    pub local_user: String,
+    /// The remote server as `host:port`.
+    pub server: String,
+    pub username: String,
+    pub password: String,
+    pub security: Security,
+    /// Delete messages on the remote server once they are delivered.
+    pub delete: bool,
+}
+
+impl FetchAccount {
+    fn key(&self) -> String {
+        format!("{}\0{}@{}", self.local_user, self.username, self.server)
+    }
+
+    fn seen_key(&self, uidl: &str) -> String {
+        format!("{}\0{}", self.key(), uidl)
+    }
+
+    fn host(&self) -> &str {
+        self.server
+            .rsplit_once(':')
+            .map_or(self.server.as_str(), |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+    }
+}
+
+#[derive(Clone)]
+pub struct FetchStore {
+    accounts: sled::Tree,
+    seen: sled::Tree,
+}
+
+impl FetchStore {
+    pub fn new(db: &sled::Db) -> Result<Self, sled::Error> {
+        Ok(Self {
+            accounts: db.open_tree(ACCOUNTS_TREE)?,
+            seen: db.open_tree(SEEN_TREE)?,
+        })
+    }
+
+    /// Adds an account, replacing one for the same local user, remote user
+    /// and server.
+    pub fn add_account(&self, account: &FetchAccount) -> IOResult<()> {
+        let value = serde_json::to_vec(account)?;
+        self.accounts.insert(account.key(), value)?;
+        Ok(())
+    }
+
+    pub fn accounts(&self) -> IOResult<Vec<FetchAccount>> {
+        self.accounts
+            .iter()
+            .values()
+            .map(|value| Ok(serde_json::from_slice(&value?)?))
+            .collect()
+    }
+
+    pub fn is_seen(&self, account: &FetchAccount, uidl: &str) -> Result<bool, sled::Error> {
+        self.seen.contains_key(account.seen_key(uidl))
+    }
+
+    pub fn mark_seen(&self, account: &FetchAccount, uidl: &str) -> Result<(), sled::Error> {
+        self.seen.insert(account.seen_key(uidl), &[])?;
+        Ok(())
+    }
+
+    /// Forgets the seen UIDLs that are no longer on the remote server, so the
+    /// store does not grow without bound.
+    pub fn retain_seen(
+        &self,
+        account: &FetchAccount,
+        remote: &HashSet<&str>,
+    ) -> Result<(), sled::Error> {
+        let prefix = format!("{}\0", account.key());
+        for key in self.seen.scan_prefix(&prefix).keys() {
+            let key = key?;
+            let uidl = String::from_utf8_lossy(&key[prefix.len()..]);
+            if !remote.contains(uidl.as_ref()) {
+                self.seen.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fetches new mail for every account at once, so a slow server only
+/// delays its own account. Failures are logged and do not affect the other
+/// accounts.
+pub async fn fetch_all(store: &FetchStore, tls_config: Option<&Arc<ClientConfig>>) {
+    let accounts = {
+        let store = store.clone();
+        crate::blocking(move || store.accounts()).await
+    };
+    let accounts = match accounts {
+        Ok(accounts) => accounts,
+        Err(e) => {
+            eprintln!("Error reading fetch accounts: {}", e);
+            return;
+        }
+    };
+    let mut polls = JoinSet::new();
+    for account in accounts {
+        let store = store.clone();
+        let tls_config = tls_config.cloned();
+        polls.spawn(async move {
+            let result = tokio::time::timeout(
+                ACCOUNT_TIMEOUT,
+                fetch_account(&store, &account, tls_config.as_ref()),
+            )
+            .await
+            .unwrap_or_else(|_| Err(io::Error::from(io::ErrorKind::TimedOut).into()));
+            match result {
+                Ok(0) => {}
+                Ok(count) => println!(
+                    "fetched {} message(s) from {}@{} for {}",
+                    count, account.username, account.server, account.local_user
+                ),
+                Err(e) => eprintln!(
+                    "error fetching {}@{} for {}: {}",
+                    account.username, account.server, account.local_user, e
+                ),
+            }
+        });
+    }
+    while polls.join_next().await.is_some() {}
+}
+
+/// Connects to an account's server and delivers the messages not yet seen.
+/// Returns how many were delivered.
+pub async fn fetch_account(
+    store: &FetchStore,
+    account: &FetchAccount,
+    tls_config: Option<&Arc<ClientConfig>>,
+) -> pop3_client::Result<usize> {
+    match account.security {
+        Security::Plain => {
+            let client = Client::connect(&account.server).await?;
+            fetch_messages(client, store, account).await
+        }
+        Security::Tls => {
+            let (server_name, config) = tls_params(account, tls_config)?;
+            let client = Client::connect_tls(&account.server, server_name, config).await?;
+            fetch_messages(client, store, account).await
+        }
+        Security::Stls => {
+            let (server_name, config) = tls_params(account, tls_config)?;
+            let client = Client::connect(&account.server).await?;
+            let client = client.stls(server_name, config).await?;
+            fetch_messages(client, store, account).await
+        }
+    }
+}
+
+fn tls_params(
+    account: &FetchAccount,
+    tls_config: Option<&Arc<ClientConfig>>,
+) -> IOResult<(ServerName<'static>, Arc<ClientConfig>)> {
+    let config = tls_config
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no CA certificates loaded"))?;
+    let server_name = ServerName::try_from(account.host().to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok((server_name, Arc::clone(config)))
+}
+
+async fn fetch_messages<S>(
+    mut client: Client<S>,
+    store: &FetchStore,
+    account: &FetchAccount,
+) -> pop3_client::Result<usize>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    client.login(&account.username, &account.password).await?;
+    let mailbox = Arc::new(MailDir::new(&account.local_user)?);
+    let listing = client.uidl().await?;
+    let seen = {
+        let store = store.clone();
+        let account = account.clone();
+        let uidls: Vec<String> = listing.iter().map(|(_, uidl)| uidl.clone()).collect();
+        crate::blocking(move || {
+            let remote = uidls.iter().map(String::as_str).collect();
+            store.retain_seen(&account, &remote)?;
+            uidls
+                .iter()
+                .map(|uidl| store.is_seen(&account, uidl))
+                .collect::<Result<Vec<_>, _>>()
+        })
+        .await
+        .map_err(io::Error::from)?
+    };
+
+    let mut delivered = 0;
+    for ((id, uidl), seen) in listing.iter().zip(seen) {
+        if !seen {
+            let message = client.retr(*id).await?;
+            let mailbox = Arc::clone(&mailbox);
+            let store = store.clone();
+            let account = account.clone();
+            let uidl = uidl.clone();
+            crate::blocking(move || -> io::Result<()> {
+                mailbox.deliver(&to_lf(&message))?;
+                store.mark_seen(&account, &uidl)?;
+                Ok(())
+            })
+            .await?;
+            delivered += 1;
+        }
+        // A message seen on an earlier poll is still here if that poll's
+        // QUIT failed, so its DELE never took effect; delete it now.
+        if account.delete {
+            client.dele(*id).await?;
+        }
+    }
+    client.quit().await?;
+    Ok(delivered)
+}
+
+/// Converts a message from the CRLF wire form to the LF line endings used on
+/// disk.
+fn to_lf(message: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(message.len());
+    let mut bytes = message.iter().peekable();
+    while let Some(&b) = bytes.next() {
+        if b == b'\r' && bytes.peek() == Some(&&b'\n') {
+            continue;
+        }
+        out.push(b);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, DuplexStream};
+
+    fn account(local_user: &str) -> FetchAccount {
+        FetchAccount {
+            local_user: local_user.to_string(),
+            server: "pop.example.com:995".to_string(),
+            username: "remote".to_string(),
+            password: "secret".to_string(),
+            security: Security::Tls,
+            delete: false,
+        }
+    }
+
+    #[test]
+    fn test_fetch_store_tracks_seen_uidls_per_account() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = FetchStore::new(&db).unwrap();
+        let alice = account("alice");
+        let bob = account("bob");
+        store.add_account(&alice).unwrap();
+        store.add_account(&bob).unwrap();
+        store.add_account(&alice).unwrap();
+        assert_eq!(store.accounts().unwrap(), vec![alice.clone(), bob.clone()]);
+        assert_eq!(alice.host(), "pop.example.com");
+
+        store.mark_seen(&alice, "uid-1").unwrap();
+        store.mark_seen(&alice, "uid-2").unwrap();
+        assert!(store.is_seen(&alice, "uid-1").unwrap());
+        assert!(!store.is_seen(&bob, "uid-1").unwrap());
+
+        store
+            .retain_seen(&alice, &HashSet::from(["uid-2"]))
+            .unwrap();
+        assert!(!store.is_seen(&alice, "uid-1").unwrap());
+        assert!(store.is_seen(&alice, "uid-2").unwrap());
+    }
+
+    /// Plays a remote POP3 server: sends the greeting, then checks each
+    /// command the fetcher sends and answers it.
+    async fn serve(stream: DuplexStream, script: &[(&str, &str)]) {
+        let mut stream = BufReader::new(stream);
+        stream.write_all(b"+OK ready\r\n").await.unwrap();
+        for (expected, response) in script {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            assert_eq!(line, format!("{}\r\n", expected));
+            stream.write_all(response.as_bytes()).await.unwrap();
+        }
+    }
+
+    async fn poll(
+        store: &FetchStore,
+        account: &FetchAccount,
+        script: &[(&str, &str)],
+    ) -> pop3_client::Result<usize> {
+        let (client, server) = tokio::io::duplex(4096);
+        let (result, ()) = tokio::join!(
+            async {
+                let client = Client::from_stream(client).await?;
+                fetch_messages(client, store, account).await
+            },
+            serve(server, script)
+        );
+        result
+    }
+
+    #[tokio::test]
+    async fn test_delete_is_retried_after_failed_quit() {
+        let _maildir = crate::tests::MAILDIR_BASE.lock().await;
+        let local_user = format!("fetch-test-{}", std::process::id());
+        maildir::init_user_mailbox(&local_user).unwrap();
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = FetchStore::new(&db).unwrap();
+        let account = FetchAccount {
+            delete: true,
+            ..account(&local_user)
+        };
+        let login = [("USER remote", "+OK\r\n"), ("PASS secret", "+OK\r\n")];
+        let listing = ("UIDL", "+OK\r\n1 uid-1\r\n2 uid-2\r\n.\r\n");
+
+        // The connection drops at QUIT, so neither DELE takes effect.
+        let first = [
+            listing,
+            ("RETR 1", "+OK\r\nSubject: one\r\n\r\nbody\r\n.\r\n"),
+            ("DELE 1", "+OK\r\n"),
+            ("RETR 2", "+OK\r\nSubject: two\r\n\r\nbody\r\n.\r\n"),
+            ("DELE 2", "+OK\r\n"),
+            ("QUIT", "-ERR [SYS/TEMP] Unable to remove messages\r\n"),
+        ];
+        let result = poll(&store, &account, &[&login[..], &first[..]].concat()).await;
+        assert!(result.is_err(), "a failed QUIT should be reported");
+        let new = format!("Maildir/{}/new", local_user);
+        assert_eq!(std::fs::read_dir(&new).unwrap().count(), 2);
+
+        // The next poll deletes the messages without delivering them again.
+        let second = [
+            listing,
+            ("DELE 1", "+OK\r\n"),
+            ("DELE 2", "+OK\r\n"),
+            ("QUIT", "+OK\r\n"),
+        ];
+        let result = poll(&store, &account, &[&login[..], &second[..]].concat()).await;
+        assert_eq!(result.unwrap(), 0);
+        assert_eq!(std::fs::read_dir(&new).unwrap().count(), 2);
+
+        std::fs::remove_dir_all(format!("Maildir/{}", local_user)).unwrap();
+        let _ = std::fs::remove_dir("Maildir");
+    }
+
+    #[test]
+    fn test_to_lf() {
+        assert_eq!(to_lf(b"a\r\nb\r\n\r\nc\rd"), b"a\nb\n\nc\rd");
+    }
+}
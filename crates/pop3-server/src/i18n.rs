@@ -0,0 +1,208 @@
+use pop3_proto::StatusIndicator;
+
+/// A language selectable with LANG (RFC 6856) and its message catalog. The
+/// catalog maps the English response text to its translation; responses that
+/// are not in the catalog are sent in English.
+pub struct Language {
+    pub tag: &'static str,
+    pub description: &'static str,
+    messages: &'static [(&'static str, &'static str)],
+}
+
+pub const ENGLISH: Language = Language {
+    tag: "en",
+    description: "English",
+    messages: &[],
+};
+
+pub const GERMAN: Language = Language {
+    tag: "de",
+    description: "Deutsch",
+    messages: &[
+        ("Authentication cancelled", "Authentifizierung abgebrochen"),
+        (
+            "Autologout; idle for too long",
+            "Automatische Abmeldung wegen Inaktivität",
+        ),
+        ("Authentication failed", "Authentifizierung fehlgeschlagen"),
+        ("Begin TLS negotiation", "TLS-Aushandlung beginnt"),
+        ("Bye!", "Auf Wiedersehen!"),
+        (
+            "Command line is not valid UTF-8",
+            "Befehlszeile ist kein gültiges UTF-8",
+        ),
+        ("Command line too long", "Befehlszeile zu lang"),
+        (
+            "Command not permitted when TLS active",
+            "Befehl bei aktivem TLS nicht erlaubt",
+        ),
+        ("Invalid base64 response", "Ungültige Base64-Antwort"),
+        ("Language changed", "Sprache geändert"),
+        ("Mailbox already in use", "Postfach wird bereits verwendet"),
+        ("Mailbox locked and ready", "Postfach gesperrt und bereit"),
+        (
+            "No username set - send USER first",
+            "Kein Benutzername gesetzt - zuerst USER senden",
+        ),
+        (
+            "Session not in Authorization state",
+            "Sitzung nicht im Authorization-Zustand",
+        ),
+        (
+            "Session not in Transaction state",
+            "Sitzung nicht im Transaction-Zustand",
+        ),
+        ("TLS not available", "TLS nicht verfügbar"),
+        (
+            "Too many failed logins",
+            "Zu viele fehlgeschlagene Anmeldungen",
+        ),
+        ("Too many invalid commands", "Zu viele ungültige Befehle"),
+        ("Unknown command", "Unbekannter Befehl"),
+        (
+            "Unsupported authentication mechanism",
+            "Nicht unterstützter Authentifizierungsmechanismus",
+        ),
+        ("User accepted", "Benutzer akzeptiert"),
+        (
+            "Username or password are incorrect",
+            "Benutzername oder Passwort falsch",
+        ),
+        ("no such message", "Nachricht existiert nicht"),
+    ],
+};
+
+pub const FRENCH: Language = Language {
+    tag: "fr",
+    description: "Français",
+    messages: &[
+        ("Authentication cancelled", "Authentification annulée"),
+        (
+            "Autologout; idle for too long",
+            "Déconnexion automatique pour inactivité",
+        ),
+        ("Authentication failed", "Échec de l'authentification"),
+        ("Begin TLS negotiation", "Début de la négociation TLS"),
+        ("Bye!", "Au revoir !"),
+        (
+            "Command line is not valid UTF-8",
+            "La ligne de commande n'est pas en UTF-8 valide",
+        ),
+        ("Command line too long", "Ligne de commande trop longue"),
+        (
+            "Command not permitted when TLS active",
+            "Commande non autorisée lorsque TLS est actif",
+        ),
+        ("Invalid base64 response", "Réponse base64 invalide"),
+        ("Language changed", "Langue modifiée"),
+        ("Mailbox already in use", "Boîte aux lettres déjà utilisée"),
+        (
+            "Mailbox locked and ready",
+            "Boîte aux lettres verrouillée et prête",
+        ),
+        (
+            "No username set - send USER first",
+            "Aucun nom d'utilisateur - envoyez d'abord USER",
+        ),
+        (
+            "Session not in Authorization state",
+            "Session pas dans l'état Authorization",
+        ),
+        (
+            "Session not in Transaction state",
+            "Session pas dans l'état Transaction",
+        ),
+        ("TLS not available", "TLS non disponible"),
+        ("Too many failed logins", "Trop d'échecs de connexion"),
+        ("Too many invalid commands", "Trop de commandes invalides"),
+        ("Unknown command", "Commande inconnue"),
+        (
+            "Unsupported authentication mechanism",
+            "Mécanisme d'authentification non pris en charge",
+        ),
+        ("User accepted", "Utilisateur accepté"),
+        (
+            "Username or password are incorrect",
+            "Nom d'utilisateur ou mot de passe incorrect",
+        ),
+        ("no such message", "message inexistant"),
+    ],
+};
+
+pub const LANGUAGES: &[Language] = &[ENGLISH, GERMAN, FRENCH];
+
+/// Finds a supported language for a language tag, falling back from a
+/// regional tag such as `de-AT` to its primary language.
+pub fn find(tag: &str) -> Option<&'static Language> {
+    let primary = tag.split('-').next().unwrap_or(tag);
+    LANGUAGES
+        .iter()
+        .find(|l| l.tag.eq_ignore_ascii_case(tag))
+        .or_else(|| {
+            LANGUAGES
+                .iter()
+                .find(|l| l.tag.eq_ignore_ascii_case(primary))
+        })
+}
+
+impl Language {
+    fn translate(&self, msg: String) -> String {
+        self.messages
+            .iter()
+            .find(|(english, _)| *english == msg.trim_end())
+            .map(|(_, translated)| translated.to_string())
+            .unwrap_or(msg)
+    }
+
+    /// Translates the human-readable text of a response. Response codes,
+    /// SASL continuations and multi-line listings are never translated.
+    pub fn localize(&self, resp: StatusIndicator) -> StatusIndicator {
+        match resp {
+            StatusIndicator::Ok(msg) => StatusIndicator::Ok(self.translate(msg)),
+            StatusIndicator::Err(msg) => StatusIndicator::Err(self.translate(msg)),
+            StatusIndicator::ErrCode(code, msg) => {
+                StatusIndicator::ErrCode(code, self.translate(msg))
+            }
+            resp @ (StatusIndicator::Continue(_) | StatusIndicator::MultiLine(_)) => resp,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pop3_proto::ResponseCode;
+
+    #[test]
+    fn test_find_and_localize() {
+        assert_eq!(find("DE").unwrap().tag, "de");
+        assert_eq!(find("fr-CA").unwrap().tag, "fr");
+        assert!(find("i-default").is_none());
+
+        let german = find("de").unwrap();
+        let resp = german.localize(StatusIndicator::ErrCode(
+            ResponseCode::InUse,
+            "Mailbox already in use".to_string(),
+        ));
+        assert_eq!(
+            resp.to_string(),
+            "-ERR [IN-USE] Postfach wird bereits verwendet\r\n"
+        );
+
+        let resp = german.localize(StatusIndicator::Err(
+            "Session not in Transaction state ".to_string(),
+        ));
+        assert_eq!(
+            resp.to_string(),
+            "-ERR Sitzung nicht im Transaction-Zustand\r\n",
+            "trailing whitespace should not prevent a catalog match"
+        );
+
+        let resp = german.localize(StatusIndicator::Ok("2 320".to_string()));
+        assert_eq!(
+            resp.to_string(),
+            "+OK 2 320\r\n",
+            "messages missing from the catalog should be sent unchanged"
+        );
+    }
+}
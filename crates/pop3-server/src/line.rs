@@ -0,0 +1,107 @@
+use tokio::io::{AsyncBufRead, AsyncBufReadExt};
+
+use crate::IOResult;
+
+/// The outcome of reading one line from the client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Line {
+    /// A line, held in the buffer with its terminator. The last line before
+    /// EOF may lack one.
+    Complete,
+    /// The line exceeded the limit. It was consumed up to its terminator but
+    /// not kept.
+    TooLong,
+    /// The client closed the connection.
+    Eof,
+}
+
+/// Reads a line of at most `limit` octets, including its terminator, into
+/// `buf`. Unlike `read_line`, an overlong line is discarded as it arrives
+/// instead of being buffered, so memory use stays bounded.
+pub async fn read_line<R>(reader: &mut R, buf: &mut Vec<u8>, limit: usize) -> IOResult<Line>
+where
+    R: AsyncBufRead + Unpin,
+{
+    buf.clear();
+    let mut too_long = false;
+    loop {
+        let available = reader.fill_buf().await?;
+        if available.is_empty() {
+            return Ok(match (too_long, buf.is_empty()) {
+                (true, _) => Line::TooLong,
+                (false, true) => Line::Eof,
+                (false, false) => Line::Complete,
+            });
+        }
+        let newline = available.iter().position(|&b| b == b'\n');
+        let chunk = match newline {
+            Some(i) => &available[..=i],
+            None => available,
+        };
+        let consumed = chunk.len();
+        if !too_long {
+            if buf.len() + consumed > limit {
+                too_long = true;
+                buf.clear();
+            } else {
+                buf.extend_from_slice(chunk);
+            }
+        }
+        reader.consume(consumed);
+        if newline.is_some() {
+            return Ok(if too_long {
+                Line::TooLong
+            } else {
+                Line::Complete
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::BufReader;
+
+    #[tokio::test]
+    async fn test_read_line_enforces_limit() {
+        let long = "X".repeat(300);
+        let input = format!("NOOP\r\n{}\r\nSTAT\r\nQUIT", long);
+        // A tiny buffer makes the overlong line span many reads.
+        let mut reader = BufReader::with_capacity(16, input.as_bytes());
+        let mut buf = Vec::new();
+
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::Complete
+        );
+        assert_eq!(buf, b"NOOP\r\n");
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::TooLong
+        );
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::Complete,
+            "reading should resume after the discarded line"
+        );
+        assert_eq!(buf, b"STAT\r\n");
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::Complete
+        );
+        assert_eq!(buf, b"QUIT", "a final line without a terminator is kept");
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::Eof
+        );
+
+        let exact = format!("{}\r\n", "X".repeat(253));
+        let mut reader = BufReader::new(exact.as_bytes());
+        assert_eq!(
+            read_line(&mut reader, &mut buf, 255).await.unwrap(),
+            Line::Complete,
+            "a line of exactly the limit including CRLF is allowed"
+        );
+    }
+}
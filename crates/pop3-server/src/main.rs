@@ -1,54 +1,212 @@
-pub mod protocol;
+pub mod capability;
+pub mod fetch;
+pub mod i18n;
+pub mod line;
+pub mod proxy;
+pub mod sasl;
+pub mod tls;
 
 use std::{
-    collections::{BTreeMap, HashSet},
-    sync::{Arc, Mutex},
+    collections::HashSet,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use protocol::{Command, SessionState, StatusIndicator};
+use capability::{Availability, Capability, CapabilityRegistry, TlsRequirement};
+use i18n::Language;
+use line::{Line, read_line};
 use tokio::{
-    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter},
     net::{TcpListener, TcpStream},
 };
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
 
 use auth::AuthStore;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use maildir::{MailDir, MailEntry};
+use pop3_proto::{
+    Action, Command, CredentialCheck, MailboxError, MessageEncoder, MessageInfo, MultiLine,
+    ResponseCode, SessionMachine, StatusIndicator,
+};
+use sasl::{Mechanism, SaslContext, SaslRegistry, Step};
 
 pub type IOResult<T> = std::io::Result<T>;
 
-struct MailboxCache {
-    messages: BTreeMap<u64, MailEntry>,
-    total_octets: u64,
+/// The most responses queued for a pipelining client before they are
+/// flushed, bounding how much a burst of commands can buffer.
+const MAX_PIPELINED_RESPONSES: usize = 64;
+
+/// How much of a message file is read and encoded at a time by RETR and TOP.
+const MESSAGE_CHUNK_SIZE: usize = 64 * 1024;
+
+/// RFC 2449 section 4: a command line, including its CRLF, is at most 255
+/// octets.
+const MAX_COMMAND_LINE: usize = 255;
+
+/// The longest SASL response line accepted during AUTH.
+const MAX_SASL_RESPONSE_LINE: usize = 16 * 1024;
+
+/// How many invalid commands in a row a client may send before it is
+/// disconnected.
+const MAX_INVALID_COMMANDS: usize = 10;
+
+/// How many rejected PASS, APOP or AUTH attempts a client may make before it
+/// is disconnected, slowing down password guessing.
+const MAX_FAILED_LOGINS: usize = 3;
+
+/// RFC 1939 section 3: an inactivity autologout timer must be at least 10
+/// minutes. This is also the default.
+const MIN_AUTOLOGOUT: Duration = Duration::from_secs(10 * 60);
+
+pub struct Session {
+    /// The protocol state; everything below is what the host keeps for it.
+    machine: SessionMachine,
+    mailbox_lock: Option<MailboxLock>,
+    /// The maildrop, where message `n` is at index `n - 1`.
+    messages: Vec<MailEntry>,
+    /// The `tls-exporter` channel binding data once TLS is established.
+    channel_binding: Option<Vec<u8>>,
+    apop_timestamp: String,
+    /// The SASL exchange in progress, if the client is mid-AUTH.
+    sasl: Option<Box<dyn Mechanism>>,
+    /// The language selected with LANG.
+    language: &'static Language,
 }
 
-impl MailboxCache {
-    fn new(maildir: &MailDir) -> Self {
-        let entries = maildir.list_messages();
-        let mut messages = BTreeMap::new();
-        let mut total_octets = 0;
-        for (i, entry) in entries.into_iter().enumerate() {
-            let id = (i as u64) + 1;
-            total_octets += entry.size;
-            messages.insert(id, entry);
-        }
+impl Session {
+    fn new(apop_timestamp: String, tls_available: bool, tls_active: bool) -> Self {
         Self {
-            messages,
-            total_octets,
+            machine: SessionMachine::new(tls_available, tls_active),
+            mailbox_lock: None,
+            messages: Vec::new(),
+            channel_binding: None,
+            apop_timestamp,
+            sasl: None,
+            language: &i18n::ENGLISH,
         }
     }
 }
 
-pub struct Session {
-    state: SessionState,
-    mailbox_lock: Option<MailboxLock>,
-    cache: Option<MailboxCache>,
-    messages_marked_for_deletion: HashSet<u64>,
+/// What `process` sends in answer to a client line.
+enum Reply {
+    Status(StatusIndicator),
+    Message(MessageBody),
+    /// Send the response, then upgrade the connection to TLS.
+    StartTls(StatusIndicator),
+    /// Send the response, then end the session.
+    Close(StatusIndicator),
+    /// Log in to the user's upstream server and relay the rest of the
+    /// session to it.
+    Proxy {
+        username: String,
+        password: String,
+    },
+}
+
+/// A RETR or TOP response whose body is streamed from the message file when
+/// the response is written, rather than held in memory.
+struct MessageBody {
+    status: String,
+    file: std::fs::File,
+    encoder: MessageEncoder,
+}
+
+/// State shared by every connection the server accepts.
+pub struct ServerContext {
+    session_manager: Arc<SessionManager>,
+    auth_store: AuthStore,
+    capabilities: CapabilityRegistry,
+    sasl: SaslRegistry,
+    tls_acceptor: Option<TlsAcceptor>,
+    /// How long a client may stay idle before the session is closed.
+    autologout: Duration,
+    /// Set when running as a proxy in front of upstream servers.
+    proxy: Option<proxy::ProxyConfig>,
+}
+
+/// Why a session ended, for the connection log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EndReason {
+    Quit,
+    /// The client closed the connection without QUIT.
+    ClientClosed,
+    /// The autologout timer expired.
+    Timeout,
+    TooManyInvalidCommands,
+    TooManyFailedLogins,
+    /// A proxied session was closed by the client or the upstream server.
+    Relayed,
+}
+
+impl std::fmt::Display for EndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            EndReason::Quit => "client quit",
+            EndReason::ClientClosed => "client closed the connection",
+            EndReason::Timeout => "autologout timer expired",
+            EndReason::TooManyInvalidCommands => "too many invalid commands",
+            EndReason::TooManyFailedLogins => "too many failed logins",
+            EndReason::Relayed => "proxied connection closed",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// How a call to `process` ended.
+enum SessionEnd<S> {
+    Closed,
+    /// The client issued STLS; the stream must be upgraded before the
+    /// session continues.
+    StartTls(S),
+}
+
+/// Builds a unique `<pid.clock@hostname>` banner timestamp for APOP. The
+/// clock is the current time in nanoseconds, bumped if needed so that no two
+/// connections are ever handed the same value.
+fn apop_timestamp() -> String {
+    static LAST_CLOCK: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let clock = LAST_CLOCK
+        .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| {
+            Some(now.max(last + 1))
+        })
+        .map(|last| now.max(last + 1))
+        .unwrap_or(now);
+    let hostname = gethostname::gethostname();
+    format!(
+        "<{}.{}@{}>",
+        std::process::id(),
+        clock,
+        hostname.to_string_lossy()
+    )
+}
+
+/// The greeting. It carries the APOP timestamp, except in proxy mode, where
+/// APOP is not offered.
+fn greeting(ctx: &ServerContext, apop_timestamp: &str) -> StatusIndicator {
+    if ctx.proxy.is_some() {
+        StatusIndicator::Ok("POP3 proxy ready".to_string())
+    } else {
+        StatusIndicator::Ok(format!("POP3 server ready {}", apop_timestamp))
+    }
 }
 
 pub struct SessionManager {
     locked_mailboxes: Mutex<HashSet<String>>,
 }
 
+impl Default for SessionManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SessionManager {
     pub fn new() -> Self {
         Self {
@@ -93,8 +251,142 @@ impl Drop for MailboxLock {
 #[tokio::main]
 async fn main() {
     let args: Vec<String> = std::env::args().collect();
+
+    // The fetcher keeps its own database so it can run next to the server,
+    // which holds "my_db" open.
+    if args.len() >= 2 && args[1] == "fetch" {
+        let once = match args.len() {
+            2 => false,
+            3 if args[2] == "--once" => true,
+            _ => {
+                eprintln!("Usage: {} fetch [--once]", args[0]);
+                std::process::exit(1);
+            }
+        };
+        run_fetcher(once).await;
+        return;
+    }
+
     let db = sled::open("my_db").unwrap();
 
+    if args.len() >= 2 && args[1] == "add-fetch-account" {
+        let usage = format!(
+            "Usage: {} add-fetch-account <username> <host:port> <remote-user> <remote-password> [--tls|--stls] [--delete]",
+            args[0]
+        );
+        if args.len() < 6 {
+            eprintln!("{}", usage);
+            std::process::exit(1);
+        }
+        let mut security = fetch::Security::Plain;
+        let mut delete = false;
+        for flag in &args[6..] {
+            match flag.as_str() {
+                "--tls" => security = fetch::Security::Tls,
+                "--stls" => security = fetch::Security::Stls,
+                "--delete" => delete = true,
+                _ => {
+                    eprintln!("{}", usage);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        let auth_store = AuthStore::new(db);
+        match auth_store.user_exists(&args[2]) {
+            Ok(true) => {}
+            Ok(false) => {
+                println!("User '{}' does not exist", args[2]);
+                return;
+            }
+            Err(e) => {
+                eprintln!("Error looking up user: {}", e);
+                return;
+            }
+        }
+
+        let account = fetch::FetchAccount {
+            local_user: args[2].clone(),
+            server: args[3].clone(),
+            username: args[4].clone(),
+            password: args[5].clone(),
+            security,
+            delete,
+        };
+        let fetch_db = match sled::open(fetch::FETCH_DB) {
+            Ok(db) => db,
+            Err(e) => {
+                // sled locks the database, and the fetcher holds it while
+                // polling.
+                eprintln!(
+                    "Error opening {}: {} (if the fetcher is polling, try again shortly)",
+                    fetch::FETCH_DB,
+                    e
+                );
+                std::process::exit(1);
+            }
+        };
+        let result = fetch::FetchStore::new(&fetch_db)
+            .map_err(std::io::Error::from)
+            .and_then(|store| store.add_account(&account));
+        match result {
+            Ok(()) => println!("Fetch account added for user '{}'", args[2]),
+            Err(e) => eprintln!("Error adding fetch account: {}", e),
+        }
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "set-apop-secret" {
+        if args.len() != 4 {
+            eprintln!("Usage: {} set-apop-secret <username> <secret>", args[0]);
+            std::process::exit(1);
+        }
+
+        let auth_store = AuthStore::new(db);
+
+        match auth_store.set_apop_secret(&args[2], &args[3]) {
+            Ok(true) => println!("APOP secret set for user '{}'", args[2]),
+            Ok(false) => println!("User '{}' does not exist", args[2]),
+            Err(e) => eprintln!("Error setting APOP secret: {}", e),
+        }
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "change-password" {
+        if args.len() != 4 {
+            eprintln!("Usage: {} change-password <username> <password>", args[0]);
+            std::process::exit(1);
+        }
+
+        let auth_store = AuthStore::new(db);
+
+        match auth_store.change_password(&args[2], &args[3]) {
+            Ok(true) => println!("Password changed for user '{}'", args[2]),
+            Ok(false) => println!("User '{}' does not exist", args[2]),
+            Err(e) => eprintln!("Error changing password: {}", e),
+        }
+        return;
+    }
+
+    if args.len() >= 2 && args[1] == "set-route" {
+        if args.len() != 4 {
+            eprintln!("Usage: {} set-route <username> <upstream>", args[0]);
+            std::process::exit(1);
+        }
+        if proxy::Upstream::parse(&args[3]).is_none() {
+            eprintln!("Upstream must be a pop3://, pop3s:// or pop3+stls:// URL");
+            std::process::exit(1);
+        }
+
+        let auth_store = AuthStore::new(db);
+
+        match auth_store.set_route(&args[2], &args[3]) {
+            Ok(()) => println!("User '{}' routed to {}", args[2], args[3]),
+            Err(e) => eprintln!("Error setting route: {}", e),
+        }
+        return;
+    }
+
     if args.len() >= 2 && args[1] == "add-user" {
         if args.len() != 4 {
             eprintln!("Usage: {} add-user <username> <password>", args[0]);
@@ -114,254 +406,1169 @@ async fn main() {
         return;
     }
 
-    let auth = Arc::new(AuthStore::new(db));
-    let session_manager = Arc::new(SessionManager::new());
-    let listener = TcpListener::bind("127.0.0.1:1110").await.unwrap();
+    // In proxy mode USER and PASS are checked by the upstream server the
+    // user is routed to. APOP and SASL cannot be passed on, so they are off.
+    let proxy = match std::env::var("POP3_PROXY").as_deref() {
+        Ok("on") => Some(proxy::ProxyConfig {
+            tls_config: upstream_tls_config("POP3_PROXY_CA"),
+        }),
+        Ok("off") | Err(_) => None,
+        Ok(_) => {
+            eprintln!("POP3_PROXY must be \"on\" or \"off\"");
+            std::process::exit(1);
+        }
+    };
+
+    let tls_acceptor = match (
+        std::env::var_os("POP3_TLS_CERT"),
+        std::env::var_os("POP3_TLS_KEY"),
+    ) {
+        (Some(cert), Some(key)) => Some(tls::load_acceptor(cert.as_ref(), key.as_ref()).unwrap()),
+        _ => None,
+    };
+    let mut capabilities = capability::default_registry();
+    if tls_acceptor.is_some() {
+        capabilities.register(
+            Capability::new("STLS")
+                .available_in(Availability::Authorization)
+                .requires(TlsRequirement::PlaintextOnly),
+        );
+    }
+    let mut sasl = if proxy.is_some() {
+        sasl::SaslRegistry::new()
+    } else {
+        sasl::default_registry()
+    };
+    if let Some(jwks) = std::env::var_os("POP3_OAUTH_JWKS").filter(|_| proxy.is_none()) {
+        let (Ok(issuer), Ok(audience)) = (
+            std::env::var("POP3_OAUTH_ISSUER"),
+            std::env::var("POP3_OAUTH_AUDIENCE"),
+        ) else {
+            eprintln!("POP3_OAUTH_JWKS requires POP3_OAUTH_ISSUER and POP3_OAUTH_AUDIENCE");
+            std::process::exit(1);
+        };
+        let username_claim =
+            std::env::var("POP3_OAUTH_USERNAME_CLAIM").unwrap_or("sub".to_string());
+        let config =
+            sasl::OAuthConfig::load(jwks.as_ref(), &issuer, &audience, &username_claim).unwrap();
+        sasl::register_oauth(&mut sasl, Arc::new(config));
+    }
+    if proxy.is_none() {
+        register_sasl_capability(&mut capabilities, &sasl);
+    }
+    let autologout = match std::env::var("POP3_AUTOLOGOUT_SECS") {
+        Ok(secs) => match secs.parse::<u64>() {
+            Ok(secs) if secs >= MIN_AUTOLOGOUT.as_secs() => Duration::from_secs(secs),
+            _ => {
+                eprintln!(
+                    "POP3_AUTOLOGOUT_SECS must be at least {} seconds",
+                    MIN_AUTOLOGOUT.as_secs()
+                );
+                std::process::exit(1);
+            }
+        },
+        Err(_) => MIN_AUTOLOGOUT,
+    };
+    let ctx = Arc::new(ServerContext {
+        session_manager: Arc::new(SessionManager::new()),
+        auth_store: AuthStore::new(db),
+        capabilities,
+        sasl,
+        tls_acceptor,
+        autologout,
+        proxy,
+    });
+
+    // Each listener is enabled independently: POP3_ADDR defaults to the
+    // plaintext port and can be set to "off", POP3S_ADDR is only bound when
+    // set.
+    let plaintext_addr = std::env::var("POP3_ADDR").unwrap_or("127.0.0.1:1110".to_string());
+    let implicit_tls_addr = std::env::var("POP3S_ADDR").ok();
+
+    let mut listeners = Vec::new();
+    if plaintext_addr != "off" {
+        let listener = TcpListener::bind(&plaintext_addr).await.unwrap();
+        println!("Mail server listening on {}", plaintext_addr);
+        listeners.push(tokio::spawn(run_listener(
+            listener,
+            Arc::clone(&ctx),
+            false,
+        )));
+    }
+    if let Some(addr) = implicit_tls_addr {
+        if ctx.tls_acceptor.is_none() {
+            eprintln!("POP3S_ADDR requires POP3_TLS_CERT and POP3_TLS_KEY");
+            std::process::exit(1);
+        }
+        let listener = TcpListener::bind(&addr).await.unwrap();
+        println!("Mail server listening on {} (implicit TLS)", addr);
+        listeners.push(tokio::spawn(run_listener(listener, Arc::clone(&ctx), true)));
+    }
+    if listeners.is_empty() {
+        eprintln!("No listeners enabled");
+        std::process::exit(1);
+    }
+    for listener in listeners {
+        listener.await.unwrap();
+    }
+}
+
+/// Polls the configured fetch accounts, every POP3_FETCH_INTERVAL seconds
+/// or just once.
+async fn run_fetcher(once: bool) {
+    let interval = match std::env::var("POP3_FETCH_INTERVAL") {
+        Ok(secs) => match secs.parse::<u64>() {
+            Ok(secs) if secs > 0 => Duration::from_secs(secs),
+            _ => {
+                eprintln!("POP3_FETCH_INTERVAL must be a positive number of seconds");
+                std::process::exit(1);
+            }
+        },
+        Err(_) => fetch::DEFAULT_INTERVAL,
+    };
+    let tls_config = upstream_tls_config("POP3_FETCH_CA");
 
-    println!("Mail server listening on 127.0.0.1:1110");
+    loop {
+        // The database is only held while polling, so add-fetch-account can
+        // open it between polls.
+        match sled::open(fetch::FETCH_DB).and_then(|db| fetch::FetchStore::new(&db)) {
+            Ok(store) => fetch::fetch_all(&store, tls_config.as_ref()).await,
+            Err(e) => eprintln!("Error opening {}: {}", fetch::FETCH_DB, e),
+        }
+        if once {
+            break;
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Loads the CA certificates remote servers are verified against: the
+/// system bundle, unless the named variable points elsewhere. Without them
+/// only plaintext servers can be reached.
+fn upstream_tls_config(ca_var: &str) -> Option<Arc<rustls::ClientConfig>> {
+    let ca_path = std::env::var_os(ca_var).unwrap_or(tls::DEFAULT_CA_BUNDLE.into());
+    match tls::load_client_config(ca_path.as_ref()) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("Not loading CA certificates: {}", e);
+            None
+        }
+    }
+}
+
+async fn run_listener(listener: TcpListener, ctx: Arc<ServerContext>, implicit_tls: bool) {
     loop {
         let (stream, _addr) = listener.accept().await.unwrap();
-        let session_manager = Arc::clone(&session_manager);
-        let auth_store = Arc::clone(&auth);
+        let ctx = Arc::clone(&ctx);
         println!("new connection");
         tokio::spawn(async move {
-            let _ = process(stream, session_manager, auth_store).await;
+            let result = if implicit_tls {
+                handle_implicit_tls(stream, ctx).await
+            } else {
+                handle_plaintext(stream, ctx).await
+            };
+            if let Err(e) = result {
+                println!("session ended: {}", e);
+            }
         });
     }
 }
 
-async fn process(
-    mut stream: TcpStream,
-    session_manager: Arc<SessionManager>,
-    auth_store: Arc<AuthStore>,
-) -> IOResult<()> {
-    let (reader, writer) = stream.split();
-    let mut reader = BufReader::new(reader);
-    let mut writer = BufWriter::new(writer);
-    println!("writing greeting");
-    let greeting = StatusIndicator::Ok("POP3 server ready".to_string());
-    writer.write(greeting.to_string().as_bytes()).await?;
-    writer.flush().await?;
-
-    let mut session = Session {
-        state: SessionState::Authorization,
-        mailbox_lock: None,
-        cache: None,
-        messages_marked_for_deletion: HashSet::new(),
+/// Serves a POP3S connection, completing the TLS handshake before the
+/// greeting is sent.
+async fn handle_implicit_tls(stream: TcpStream, ctx: Arc<ServerContext>) -> IOResult<()> {
+    let Some(acceptor) = &ctx.tls_acceptor else {
+        return Ok(());
     };
-    let mut line = String::new();
+    let stream = tls_handshake(acceptor, stream, &ctx).await?;
+    let apop_timestamp = apop_timestamp();
+    let greeting = greeting(&ctx, &apop_timestamp);
+    let mut session = Session::new(apop_timestamp, true, true);
+    session.channel_binding = tls::channel_binding(&stream);
+    process(stream, &ctx, session, Some(greeting)).await?;
+    Ok(())
+}
+
+/// Serves a connection that starts in plaintext and may be upgraded with
+/// STLS.
+async fn handle_plaintext(stream: TcpStream, ctx: Arc<ServerContext>) -> IOResult<()> {
+    let apop_timestamp = apop_timestamp();
+    let greeting = greeting(&ctx, &apop_timestamp);
+    let tls_available = ctx.tls_acceptor.is_some();
+    let session = Session::new(apop_timestamp.clone(), tls_available, false);
+    if let SessionEnd::StartTls(stream) = process(stream, &ctx, session, Some(greeting)).await? {
+        let Some(acceptor) = &ctx.tls_acceptor else {
+            return Ok(());
+        };
+        let stream = tls_handshake(acceptor, stream, &ctx).await?;
+        println!("connection upgraded to TLS");
+        // RFC 2595: the client starts over in the Authorization state and
+        // the server does not send another greeting.
+        let mut session = Session::new(apop_timestamp, true, true);
+        session.channel_binding = tls::channel_binding(&stream);
+        process(stream, &ctx, session, None).await?;
+    }
+    Ok(())
+}
+
+/// Completes a TLS handshake, giving up after the autologout timer like an
+/// idle session would.
+async fn tls_handshake(
+    acceptor: &TlsAcceptor,
+    stream: TcpStream,
+    ctx: &ServerContext,
+) -> IOResult<TlsStream<TcpStream>> {
+    tokio::time::timeout(ctx.autologout, acceptor.accept(stream))
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+}
+
+async fn process<S>(
+    stream: S,
+    ctx: &ServerContext,
+    mut session: Session,
+    greeting: Option<StatusIndicator>,
+) -> IOResult<SessionEnd<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = BufReader::new(BufWriter::new(stream));
+    if let Some(greeting) = greeting {
+        println!("writing greeting");
+        send_response(&mut stream, greeting).await?;
+    }
+
+    let mut buf = Vec::new();
+    let mut unflushed = 0;
+    let mut invalid_commands = 0;
+    let mut failed_logins = 0;
 
     loop {
-        line.clear();
-        reader.read_line(&mut line).await?;
-        match Command::parse(line.trim()) {
-            Ok(cmd) => {
-                let should_quit = matches!(cmd, Command::Quit);
-                let resp = handle_command(cmd, &mut session, &session_manager, &auth_store);
-                send_response(&mut writer, resp).await?;
-                if should_quit {
-                    return Ok(());
+        // SASL responses carry base64 tokens, such as OAuth bearer tokens,
+        // that are often longer than a command line may be.
+        let limit = if session.sasl.is_some() {
+            MAX_SASL_RESPONSE_LINE
+        } else {
+            MAX_COMMAND_LINE
+        };
+        let read = tokio::time::timeout(ctx.autologout, read_line(&mut stream, &mut buf, limit));
+        let input = match read.await {
+            Ok(Ok(Line::Complete)) => {
+                std::str::from_utf8(&buf).map_err(|_| "Command line is not valid UTF-8")
+            }
+            Ok(Ok(Line::TooLong)) => Err("Command line too long"),
+            // The session never enters the Update state, so messages marked
+            // with DELE are kept and the mailbox lock is released on drop.
+            Ok(Ok(Line::Eof)) => return Ok(end_session(&mut stream, EndReason::ClientClosed).await),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => {
+                let resp = StatusIndicator::Err("Autologout; idle for too long".to_string());
+                let _ = send_response(&mut stream, session.language.localize(resp)).await;
+                return Ok(end_session(&mut stream, EndReason::Timeout).await);
+            }
+        };
+
+        let reply = match input {
+            Ok(input) if session.sasl.is_some() => {
+                let action = handle_sasl_response(input.trim(), &mut session, ctx).await;
+                perform(action, &mut session, ctx).await
+            }
+            Ok(input) => match Command::parse(input.trim()) {
+                Ok(cmd) => {
+                    invalid_commands = 0;
+                    let action = session.machine.handle(cmd);
+                    perform(action, &mut session, ctx).await
                 }
+                Err(e) => {
+                    println!("{}", e);
+                    invalid_commands += 1;
+                    Reply::Status(e)
+                }
+            },
+            Err(msg) => {
+                // A line that cannot be read also cancels a SASL exchange.
+                session.sasl = None;
+                invalid_commands += 1;
+                Reply::Status(StatusIndicator::Err(msg.to_string()))
             }
-            Err(e) => {
-                println!("{}", e.to_string());
-                writer.write(e.to_string().as_bytes()).await?;
-                writer.flush().await?;
+        };
+        if invalid_commands >= MAX_INVALID_COMMANDS {
+            let resp = StatusIndicator::Err("Too many invalid commands".to_string());
+            let _ = send_response(&mut stream, session.language.localize(resp)).await;
+            return Ok(end_session(&mut stream, EndReason::TooManyInvalidCommands).await);
+        }
+        let mut end = None;
+        match reply {
+            Reply::Status(resp) => {
+                failed_logins += usize::from(is_failed_login(&resp));
+                write_response(&mut stream, session.language.localize(resp)).await?;
+            }
+            Reply::Message(body) => write_message(&mut stream, body).await?,
+            Reply::StartTls(resp) => {
+                write_response(&mut stream, session.language.localize(resp)).await?;
+                end = Some(SessionEnd::StartTls(()));
+            }
+            Reply::Close(resp) => {
+                write_response(&mut stream, session.language.localize(resp)).await?;
+                end = Some(SessionEnd::Closed);
+            }
+            Reply::Proxy { username, password } => {
+                match proxy_login(&username, &password, &mut session, ctx).await {
+                    Ok((upstream, _lock)) => {
+                        let resp = StatusIndicator::Ok("Logged in".to_string());
+                        send_response(&mut stream, session.language.localize(resp)).await?;
+                        // Commands the client pipelined after PASS are still
+                        // buffered and are relayed first.
+                        let relayed = proxy::relay(&mut stream, upstream, ctx.autologout).await;
+                        let reason = match relayed {
+                            Ok(()) => EndReason::Relayed,
+                            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {
+                                EndReason::Timeout
+                            }
+                            Err(e) => return Err(e),
+                        };
+                        return Ok(end_session(&mut stream, reason).await);
+                    }
+                    Err(resp) => {
+                        failed_logins += usize::from(is_failed_login(&resp));
+                        write_response(&mut stream, session.language.localize(resp)).await?;
+                    }
+                }
+            }
+        }
+
+        if failed_logins >= MAX_FAILED_LOGINS {
+            let resp = StatusIndicator::Err("Too many failed logins".to_string());
+            let _ = send_response(&mut stream, session.language.localize(resp)).await;
+            return Ok(end_session(&mut stream, EndReason::TooManyFailedLogins).await);
+        }
+
+        // RFC 2449 PIPELINING: answer every command the client has already
+        // sent before flushing, but never hold back more than a bounded
+        // batch of responses.
+        unflushed += 1;
+        if end.is_some()
+            || unflushed >= MAX_PIPELINED_RESPONSES
+            || !stream.buffer().contains(&b'\n')
+        {
+            stream.flush().await?;
+            unflushed = 0;
+        }
+        match end {
+            Some(SessionEnd::Closed) => {
+                return Ok(end_session(&mut stream, EndReason::Quit).await);
+            }
+            Some(SessionEnd::StartTls(())) => {
+                return Ok(SessionEnd::StartTls(stream.into_inner().into_inner()));
             }
+            None => {}
         }
     }
 }
 
-async fn send_response(
-    writer: &mut BufWriter<tokio::net::tcp::WriteHalf<'_>>,
-    resp: StatusIndicator,
-) -> IOResult<()> {
-    writer.write(resp.to_string().as_bytes()).await?;
+/// Advertises the SASL mechanisms, which differ before and after TLS.
+fn register_sasl_capability(capabilities: &mut CapabilityRegistry, sasl: &SaslRegistry) {
+    capabilities.register(
+        Capability::new("SASL")
+            .with_args(&sasl.names(false))
+            .available_in(Availability::Authorization)
+            .requires(TlsRequirement::PlaintextOnly),
+    );
+    capabilities.register(
+        Capability::new("SASL")
+            .with_args(&sasl.names(true))
+            .available_in(Availability::Authorization)
+            .requires(TlsRequirement::TlsOnly),
+    );
+}
+
+/// Whether a response rejects credentials given with PASS, APOP or AUTH.
+fn is_failed_login(resp: &StatusIndicator) -> bool {
+    matches!(resp, StatusIndicator::ErrCode(ResponseCode::Auth, _))
+}
+
+/// Closes the connection once a session is over and logs why it ended.
+async fn end_session<W, S>(stream: &mut W, reason: EndReason) -> SessionEnd<S>
+where
+    W: AsyncWrite + Unpin,
+{
+    let _ = stream.shutdown().await;
+    println!("session ended: {}", reason);
+    SessionEnd::Closed
+}
+
+async fn send_response<W>(writer: &mut W, resp: StatusIndicator) -> IOResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    write_response(writer, resp).await?;
     writer.flush().await?;
     Ok(())
 }
 
-fn handle_command(
-    cmd: Command,
+/// Queues a response without flushing, so pipelined responses can be sent
+/// together.
+async fn write_response<W>(writer: &mut W, resp: StatusIndicator) -> IOResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(&resp.to_bytes()).await
+}
+
+/// Streams a message file as a multi-line body, one chunk at a time. The
+/// status line is sent first, so a read error cannot be reported and ends
+/// the connection instead.
+async fn write_message<W>(writer: &mut W, body: MessageBody) -> IOResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let MessageBody {
+        status,
+        file,
+        mut encoder,
+    } = body;
+    writer
+        .write_all(format!("+OK {}\r\n", status).as_bytes())
+        .await?;
+    let mut file = tokio::fs::File::from_std(file);
+    let mut chunk = vec![0u8; MESSAGE_CHUNK_SIZE];
+    let mut out = Vec::with_capacity(MESSAGE_CHUNK_SIZE);
+    while !encoder.is_done() {
+        let n = file.read(&mut chunk).await?;
+        if n == 0 {
+            break;
+        }
+        out.clear();
+        encoder.encode(&chunk[..n], &mut out);
+        writer.write_all(&out).await?;
+    }
+    out.clear();
+    encoder.finish(&mut out);
+    out.extend_from_slice(b".\r\n");
+    writer.write_all(&out).await
+}
+
+/// Locks the user's maildrop and scans it, keeping the entries so that
+/// message numbers can be mapped back to files.
+async fn open_mailbox(
+    username: &str,
     session: &mut Session,
-    session_manager: &Arc<SessionManager>,
-    auth_store: &Arc<AuthStore>,
-) -> StatusIndicator {
-    match cmd {
-        Command::Apop => StatusIndicator::Ok("APOP".to_string()),
-        Command::User(username) => {
-            if !matches!(
-                session.state,
-                SessionState::Authorization | SessionState::AuthorizationWithUser(_)
-            ) {
-                return StatusIndicator::Err("Session not in Authorization state ".to_string());
-            }
-            session.state = SessionState::AuthorizationWithUser(username.to_string());
-            StatusIndicator::Ok("User accepted".to_string())
-        }
-        Command::Pass(password) => match &session.state {
-            SessionState::AuthorizationWithUser(username) => {
-                match auth_store.login(username, &password) {
-                    Ok(success) => {
-                        if !success {
-                            return StatusIndicator::Err(
-                                "Username or password are incorrect".to_string(),
-                            );
-                        }
-                        match session_manager
-                            .try_lock_mailbox(username, Arc::clone(session_manager))
-                        {
-                            Ok(lock) => match MailDir::new(&username) {
-                                Ok(maildir) => {
-                                    let cache = MailboxCache::new(&maildir);
-                                    session.mailbox_lock = Some(lock);
-                                    session.cache = Some(cache);
-                                    session.state =
-                                        SessionState::Transaction(username.to_string());
-                                    StatusIndicator::Ok("Pass accepted".to_string())
-                                }
-                                Err(e) => StatusIndicator::Err(format!(
-                                    "Failed to access mailbox: {}",
-                                    e
-                                )),
-                            },
-                            Err(_) => StatusIndicator::Err("Mailbox already in use".to_string()),
-                        }
-                    }
-                    Err(e) => {
-                        println!("{}", e);
-                        StatusIndicator::Err("Username or password are incorrect".to_string())
-                    }
-                }
+    ctx: &ServerContext,
+) -> Result<Vec<MessageInfo>, MailboxError> {
+    let session_manager = &ctx.session_manager;
+    let lock = session_manager
+        .try_lock_mailbox(username, Arc::clone(session_manager))
+        .map_err(|_| MailboxError::InUse)?;
+    let maildir = MailDir::new(username).map_err(|e| MailboxError::Unavailable(e.to_string()))?;
+    // Sizing the messages reads every file, so the scan is kept off the
+    // async workers.
+    let entries = blocking(move || maildir.list_messages())
+        .await
+        .map_err(|e| MailboxError::Unavailable(e.to_string()))?;
+    let messages = entries
+        .iter()
+        .map(|entry| MessageInfo {
+            size: entry.size,
+            uidl: entry.uidl.clone(),
+        })
+        .collect();
+    session.mailbox_lock = Some(lock);
+    session.messages = entries;
+    Ok(messages)
+}
+
+/// Takes the user's lock, so they cannot be active on two backends at once,
+/// and logs in to the upstream server they are routed to. On failure the
+/// state machine is told and its response returned.
+async fn proxy_login(
+    username: &str,
+    password: &str,
+    session: &mut Session,
+    ctx: &ServerContext,
+) -> Result<(proxy::Connection, MailboxLock), StatusIndicator> {
+    let Some(config) = &ctx.proxy else {
+        return Err(StatusIndicator::Err("Not a proxy".to_string()));
+    };
+    let route = match ctx.auth_store.route(username) {
+        Ok(Some(route)) => proxy::Upstream::parse(&route).ok_or_else(|| {
+            println!("invalid route for {}: {}", username, route);
+            CredentialCheck::Unavailable
+        }),
+        // Unknown users are rejected like a wrong password.
+        Ok(None) => Err(CredentialCheck::Invalid),
+        Err(e) => Err(credential_check(Err(e))),
+    };
+    let upstream = match route {
+        Ok(upstream) => upstream,
+        Err(check) => return Err(action_status(session.machine.credentials_checked(check))),
+    };
+
+    // The lock is taken first, so two logins cannot both be relayed, and is
+    // released again if the login fails. The password is checked upstream
+    // even if the user is already logged in, so only someone who knows it
+    // learns that the mailbox is in use.
+    let session_manager = &ctx.session_manager;
+    let lock = session_manager.try_lock_mailbox(username, Arc::clone(session_manager));
+    match (
+        proxy::login(&upstream, config, username, password).await,
+        lock,
+    ) {
+        (Ok(connection), Ok(lock)) => {
+            println!("proxying {} to {}", username, upstream);
+            Ok((connection, lock))
+        }
+        // Dropping the upstream connection without QUIT leaves the
+        // mailbox as it was.
+        (Ok(_), Err(_))
+        | (
+            Err(pop3_client::Error::Server {
+                code: Some(ResponseCode::InUse),
+                ..
+            }),
+            _,
+        ) => Err(mailbox_in_use(username, session)),
+        (Err(e), _) => {
+            println!("upstream login to {} failed: {}", upstream, e);
+            let check = match e {
+                pop3_client::Error::Server { .. } => CredentialCheck::Invalid,
+                _ => CredentialCheck::Unavailable,
+            };
+            Err(action_status(session.machine.credentials_checked(check)))
+        }
+    }
+}
+
+/// Reports a locked mailbox through the state machine, as if the maildrop
+/// had been opened locally.
+fn mailbox_in_use(username: &str, session: &mut Session) -> StatusIndicator {
+    let _ = session.machine.credentials_checked(CredentialCheck::Valid);
+    let action = session
+        .machine
+        .mailbox_opened(username.to_string(), Err(MailboxError::InUse));
+    action_status(action)
+}
+
+/// The response carried by a failed login's action.
+fn action_status(action: Action) -> StatusIndicator {
+    match action {
+        Action::Reply(resp) => resp,
+        other => StatusIndicator::Err(format!("Unexpected action {:?}", other)),
+    }
+}
+
+/// Maps the result of a credential lookup for the state machine.
+fn credential_check(result: Result<bool, sled::Error>) -> CredentialCheck {
+    match result {
+        Ok(true) => CredentialCheck::Valid,
+        Ok(false) => CredentialCheck::Invalid,
+        Err(e) => {
+            println!("{}", e);
+            CredentialCheck::Unavailable
+        }
+    }
+}
+
+/// Continues an in-progress AUTH exchange with the client's next line.
+async fn handle_sasl_response(line: &str, session: &mut Session, ctx: &ServerContext) -> Action {
+    let Some(mechanism) = session.sasl.take() else {
+        return Action::Reply(StatusIndicator::Err(
+            "No authentication in progress".to_string(),
+        ));
+    };
+    if line == "*" {
+        return Action::Reply(StatusIndicator::Err("Authentication cancelled".to_string()));
+    }
+    let response = match BASE64.decode(line) {
+        Ok(response) => response,
+        Err(_) => {
+            return Action::Reply(StatusIndicator::Err("Invalid base64 response".to_string()));
+        }
+    };
+    let input = SaslInput::Step(response);
+    let tls_active = session.machine.tls_active();
+    let channel_binding = session.channel_binding.clone();
+    let (step, mechanism) = run_mechanism(mechanism, input, tls_active, channel_binding, ctx).await;
+    finish_sasl_step(step, mechanism, session)
+}
+
+/// Starts an AUTH exchange with the named mechanism.
+async fn start_sasl(
+    mechanism_name: &str,
+    initial_response: Option<String>,
+    session: &mut Session,
+    ctx: &ServerContext,
+) -> Action {
+    let Some(mechanism) = ctx
+        .sasl
+        .create(mechanism_name, session.machine.tls_active())
+    else {
+        return Action::Reply(StatusIndicator::Err(
+            "Unsupported authentication mechanism".to_string(),
+        ));
+    };
+    // RFC 5034: "=" stands for an initial response of zero length.
+    let initial_response = match initial_response.as_deref() {
+        Some("=") => Some(Vec::new()),
+        Some(encoded) => match BASE64.decode(encoded) {
+            Ok(response) => Some(response),
+            Err(_) => {
+                return Action::Reply(StatusIndicator::Err("Invalid base64 response".to_string()));
             }
-            _ => StatusIndicator::Err("No username set - send USER first".to_string()),
         },
-        Command::List => match &session.state {
-            SessionState::Transaction(_) => {
-                let cache = session.cache.as_ref().unwrap();
-                let mut resp = String::new();
-                let mut total = 0;
-                for (id, entry) in &cache.messages {
-                    if session.messages_marked_for_deletion.contains(id) {
-                        continue;
-                    }
-                    resp.push_str(&format!("{} {}\r\n", id, entry.size));
-                    total += 1;
-                }
-                let resp = format!(
-                    "{} messages ({} octets)\r\n{}.",
-                    total, cache.total_octets, resp
-                );
-                StatusIndicator::Ok(resp)
+        None => None,
+    };
+    let input = SaslInput::Start(initial_response);
+    let tls_active = session.machine.tls_active();
+    let channel_binding = session.channel_binding.clone();
+    let (step, mechanism) = run_mechanism(mechanism, input, tls_active, channel_binding, ctx).await;
+    finish_sasl_step(step, mechanism, session)
+}
+
+/// What a mechanism is fed: the start of the exchange, with the initial
+/// response if there is one, or a response to its last challenge.
+enum SaslInput {
+    Start(Option<Vec<u8>>),
+    Step(Vec<u8>),
+}
+
+/// Runs a mechanism on the blocking pool, since mechanisms check password
+/// hashes and look up stored credentials.
+async fn run_mechanism(
+    mut mechanism: Box<dyn Mechanism>,
+    input: SaslInput,
+    tls_active: bool,
+    channel_binding: Option<Vec<u8>>,
+    ctx: &ServerContext,
+) -> (Step, Box<dyn Mechanism>) {
+    let auth_store = ctx.auth_store.clone();
+    blocking(move || {
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active,
+            channel_binding: channel_binding.as_deref(),
+        };
+        let step = match input {
+            SaslInput::Start(initial_response) => {
+                mechanism.start(initial_response.as_deref(), &ctx)
             }
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Retr(message_id) => match &session.state {
-            SessionState::Transaction(_) => {
-                if session.messages_marked_for_deletion.contains(&message_id) {
-                    return StatusIndicator::Err(format!(
-                        "message {} already deleted",
-                        &message_id
-                    ));
-                }
-                let cache = session.cache.as_ref().unwrap();
-                match cache.messages.get(&message_id) {
-                    Some(entry) => match entry.read() {
-                        Ok(msg) => StatusIndicator::Ok(format!("{}.", msg)),
-                        Err(e) => StatusIndicator::Err(format!("{}", e)),
-                    },
-                    None => StatusIndicator::Err("no such message".to_string()),
-                }
+            SaslInput::Step(response) => mechanism.step(&response, &ctx),
+        };
+        (step, mechanism)
+    })
+    .await
+}
+
+/// Turns the result of a mechanism step into the next action, keeping the
+/// mechanism around if it issued another challenge.
+fn finish_sasl_step(step: Step, mechanism: Box<dyn Mechanism>, session: &mut Session) -> Action {
+    match step {
+        Step::Challenge(challenge) => {
+            session.sasl = Some(mechanism);
+            Action::Reply(StatusIndicator::Continue(BASE64.encode(challenge)))
+        }
+        Step::Success(username) => session.machine.authenticated(username),
+        Step::Failure(msg) => Action::Reply(StatusIndicator::ErrCode(ResponseCode::Auth, msg)),
+        Step::TempFailure(msg) => {
+            Action::Reply(StatusIndicator::ErrCode(ResponseCode::SysTemp, msg))
+        }
+    }
+}
+
+/// Runs blocking work, such as password hashing and maildir file
+/// operations, on the blocking pool rather than an async worker. A panic in
+/// `f` is passed on as if it had run inline.
+async fn blocking<T, F>(f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    match tokio::task::spawn_blocking(f).await {
+        Ok(value) => value,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+/// Carries out the state machine's actions, feeding results back to it,
+/// until one of them produces a reply.
+async fn perform(mut action: Action, session: &mut Session, ctx: &ServerContext) -> Reply {
+    loop {
+        action = match action {
+            Action::Reply(resp) => return Reply::Status(resp),
+            Action::StartTls(resp) => return Reply::StartTls(resp),
+            Action::Close(resp) => return Reply::Close(resp),
+            Action::CheckPassword { username, password } if ctx.proxy.is_some() => {
+                return Reply::Proxy { username, password };
             }
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Dele(message_id) => match &session.state {
-            SessionState::Transaction(_) => {
-                let cache = session.cache.as_ref().unwrap();
-                if !cache.messages.contains_key(&message_id) {
-                    return StatusIndicator::Err("message does not exist".to_string());
+            Action::CheckPassword { username, password } => {
+                let auth_store = ctx.auth_store.clone();
+                let result = blocking(move || auth_store.login(&username, &password)).await;
+                session
+                    .machine
+                    .credentials_checked(credential_check(result))
+            }
+            Action::CheckApop { .. } if ctx.proxy.is_some() => {
+                // The digest is bound to our greeting and cannot be replayed
+                // upstream.
+                let _ = session
+                    .machine
+                    .credentials_checked(CredentialCheck::Invalid);
+                return Reply::Status(StatusIndicator::Err(
+                    "APOP is not available through this proxy".to_string(),
+                ));
+            }
+            Action::CheckApop { username, digest } => {
+                let auth_store = ctx.auth_store.clone();
+                let timestamp = session.apop_timestamp.clone();
+                let result =
+                    blocking(move || auth_store.verify_apop(&username, &timestamp, &digest)).await;
+                session
+                    .machine
+                    .credentials_checked(credential_check(result))
+            }
+            Action::Authenticate {
+                mechanism,
+                initial_response,
+            } => start_sasl(&mechanism, initial_response, session, ctx).await,
+            Action::OpenMailbox(username) => {
+                let mailbox = open_mailbox(&username, session, ctx).await;
+                session.machine.mailbox_opened(username, mailbox)
+            }
+            Action::SendMessage {
+                id,
+                status,
+                encoder,
+            } => {
+                let entry = session.messages[id as usize - 1].clone();
+                return match blocking(move || entry.open()).await {
+                    Ok(file) => Reply::Message(MessageBody {
+                        status,
+                        file,
+                        encoder,
+                    }),
+                    Err(e) => Reply::Status(StatusIndicator::ErrCode(
+                        ResponseCode::SysTemp,
+                        format!("{}", e),
+                    )),
+                };
+            }
+            Action::ListCapabilities => {
+                let mut resp = MultiLine::new("Capability list follows");
+                let state = session.machine.state();
+                for line in ctx.capabilities.list(state, session.machine.tls_active()) {
+                    resp.push_line(line.as_bytes());
                 }
-                if session.messages_marked_for_deletion.insert(message_id) {
-                    return StatusIndicator::Ok(format!("message {} deleted", message_id));
+                return Reply::Status(StatusIndicator::MultiLine(resp));
+            }
+            Action::ListLanguages => {
+                let mut resp = MultiLine::new("Language listing follows");
+                for language in i18n::LANGUAGES {
+                    resp.push_line(format!("{} {}", language.tag, language.description).as_bytes());
                 }
-                StatusIndicator::Err(format!("message {} already deleted", &message_id))
+                return Reply::Status(StatusIndicator::MultiLine(resp));
             }
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Rset => match &session.state {
-            SessionState::Transaction(_) => {
-                let cache = session.cache.as_ref().unwrap();
-                session.messages_marked_for_deletion.clear();
-                let resp = format!(
-                    "{} messages ({} octets)",
-                    cache.messages.len(),
-                    cache.total_octets,
-                );
-                StatusIndicator::Ok(resp)
+            Action::SetLanguage(tag) => {
+                // "*" asks the server to pick; we fall back to the default.
+                let language = if tag == "*" {
+                    Some(&i18n::ENGLISH)
+                } else {
+                    i18n::find(&tag)
+                };
+                return Reply::Status(match language {
+                    Some(language) => {
+                        session.language = language;
+                        StatusIndicator::Ok("Language changed".to_string())
+                    }
+                    None => StatusIndicator::Err("Invalid language".to_string()),
+                });
             }
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Uidl(msg_id) => match &session.state {
-            SessionState::Transaction(_) => {
-                let cache = session.cache.as_ref().unwrap();
-                match msg_id {
-                    Some(id) => {
-                        if session.messages_marked_for_deletion.contains(&id) {
-                            return StatusIndicator::Err(format!(
-                                "message {} already deleted",
-                                id
-                            ));
-                        }
-                        match cache.messages.get(&id) {
-                            Some(entry) => {
-                                StatusIndicator::Ok(format!("{} {}", id, entry.uidl))
-                            }
-                            None => StatusIndicator::Err("no such message".to_string()),
+            Action::Update { deleted, seen } => {
+                let mut messages = std::mem::take(&mut session.messages);
+                let (messages, failed) = blocking(move || {
+                    let mut failed = 0;
+                    for id in deleted {
+                        if let Err(e) = messages[id as usize - 1].delete() {
+                            println!("{}", e);
+                            failed += 1;
                         }
                     }
-                    None => {
-                        let mut resp = String::new();
-                        for (id, entry) in &cache.messages {
-                            if session.messages_marked_for_deletion.contains(id) {
-                                continue;
-                            }
-                            resp.push_str(&format!("{} {}\r\n", id, entry.uidl));
+                    // Leaving a retrieved message in new/ would show it as
+                    // new again; failing to move it is not worth failing
+                    // QUIT over.
+                    for id in seen {
+                        if let Err(e) = messages[id as usize - 1].mark_seen() {
+                            println!("{}", e);
                         }
-                        StatusIndicator::Ok(format!("\r\n{}.", resp))
                     }
+                    (messages, failed)
+                })
+                .await;
+                session.messages = messages;
+                session.machine.update_finished(failed)
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, DuplexStream};
+
+    fn context() -> ServerContext {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        ServerContext {
+            session_manager: Arc::new(SessionManager::new()),
+            auth_store: AuthStore::new(db),
+            capabilities: capability::default_registry(),
+            sasl: sasl::default_registry(),
+            tls_acceptor: None,
+            autologout: MIN_AUTOLOGOUT,
+            proxy: None,
+        }
+    }
+
+    /// Held by tests that create mailboxes under the shared `Maildir`
+    /// directory, so one test does not remove it while another fills it.
+    pub(crate) static MAILDIR_BASE: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+    async fn read_response(client: &mut BufReader<DuplexStream>) -> String {
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        line
+    }
+
+    #[tokio::test]
+    async fn test_client_closing_keeps_messages_and_releases_lock() {
+        let _maildir = MAILDIR_BASE.lock().await;
+        let username = format!("eof-test-{}", std::process::id());
+        maildir::init_user_mailbox(&username).unwrap();
+        let message = format!("Maildir/{}/new/1700000000.M1P1.host", username);
+        std::fs::write(&message, "Subject: x\n\nbody\n").unwrap();
+        let ctx = context();
+        ctx.auth_store.create_user(&username, "secret").unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            let commands = format!("USER {}\r\nPASS secret\r\nDELE 1\r\n", username);
+            client.write_all(commands.as_bytes()).await.unwrap();
+            for _ in 0..3 {
+                let line = read_response(&mut client).await;
+                assert!(line.starts_with("+OK"), "unexpected response {:?}", line);
+            }
+            // Dropping the client closes the connection without QUIT.
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+
+        assert!(
+            std::path::Path::new(&message).exists(),
+            "DELE must not take effect without QUIT"
+        );
+        assert!(
+            ctx.session_manager
+                .try_lock_mailbox(&username, Arc::clone(&ctx.session_manager))
+                .is_ok(),
+            "the mailbox lock must be released"
+        );
+        std::fs::remove_dir_all(format!("Maildir/{}", username)).unwrap();
+        let _ = std::fs::remove_dir("Maildir");
+    }
+
+    #[tokio::test]
+    async fn test_autologout() {
+        tokio::time::pause();
+        let ctx = context();
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let start = tokio::time::Instant::now();
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            client.write_all(b"USER alice\r\n").await.unwrap();
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+            let line = read_response(&mut client).await;
+            assert!(line.starts_with("-ERR Autologout"), "got {:?}", line);
+            assert_eq!(read_response(&mut client).await, "", "connection closed");
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+        assert!(start.elapsed() >= MIN_AUTOLOGOUT);
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands_answered_in_order() {
+        let ctx = context();
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            client
+                .write_all(b"USER alice\r\nCAPA\r\nNOOP\r\n")
+                .await
+                .unwrap();
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+            while read_response(&mut client).await != ".\r\n" {}
+            assert!(read_response(&mut client).await.starts_with("-ERR"));
+
+            // More commands than are answered before a flush.
+            let burst = "USER alice\r\n".repeat(MAX_PIPELINED_RESPONSES * 2 + 1) + "QUIT\r\n";
+            client.write_all(burst.as_bytes()).await.unwrap();
+            for _ in 0..MAX_PIPELINED_RESPONSES * 2 + 1 {
+                let line = read_response(&mut client).await;
+                assert!(line.starts_with("+OK"), "unexpected response {:?}", line);
+            }
+            let line = read_response(&mut client).await;
+            assert!(
+                line.starts_with("+OK"),
+                "QUIT should be answered, got {:?}",
+                line
+            );
+            assert_eq!(read_response(&mut client).await, "", "connection closed");
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_oauth_not_offered_before_stls() {
+        let mut ctx = context();
+        let config = sasl::OAuthConfig {
+            jwks: jsonwebtoken::jwk::JwkSet { keys: Vec::new() },
+            issuer: "https://sso.example.com".to_string(),
+            audience: "pop3".to_string(),
+            username_claim: "sub".to_string(),
+        };
+        sasl::register_oauth(&mut ctx.sasl, Arc::new(config));
+        register_sasl_capability(&mut ctx.capabilities, &ctx.sasl);
+
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), true, false);
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            client.write_all(b"CAPA\r\n").await.unwrap();
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+            loop {
+                let line = read_response(&mut client).await;
+                if line == ".\r\n" {
+                    break;
                 }
+                assert!(
+                    !line.contains("OAUTHBEARER") && !line.contains("XOAUTH2"),
+                    "bearer mechanisms advertised in plaintext: {:?}",
+                    line
+                );
             }
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Noop => match session.state {
-            SessionState::Transaction(_) => StatusIndicator::Ok("NOOP".to_string()),
-            _ => StatusIndicator::Err("Session not in Transaction state ".to_string()),
-        },
-        Command::Quit => {
-            match &session.state {
-                SessionState::Transaction(username) => {
-                    session.state = SessionState::Update(username.to_string());
-                    if !session.messages_marked_for_deletion.is_empty() {
-                        let cache = session.cache.as_ref().unwrap();
-                        let mut failed_to_delete = 0;
-                        for id in &session.messages_marked_for_deletion {
-                            if let Some(entry) = cache.messages.get(id) {
-                                if let Err(e) = entry.delete() {
-                                    println!("{}", e);
-                                    failed_to_delete += 1;
-                                }
-                            }
-                        }
-                        if failed_to_delete > 0 {
-                            return StatusIndicator::Err(
-                                "some deleted messages not removed".to_string(),
-                            );
-                        }
-                    }
-                    StatusIndicator::Ok("Bye!".to_string())
+            for (mechanism, message) in [
+                ("OAUTHBEARER", "n,a=tim,\x01auth=Bearer token\x01\x01"),
+                ("XOAUTH2", "user=tim\x01auth=Bearer token\x01\x01"),
+            ] {
+                let command = format!("AUTH {} {}\r\n", mechanism, BASE64.encode(message));
+                client.write_all(command.as_bytes()).await.unwrap();
+                let line = read_response(&mut client).await;
+                assert!(line.starts_with("-ERR"), "{} got {:?}", mechanism, line);
+            }
+            client.write_all(b"QUIT\r\n").await.unwrap();
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+    }
+
+    /// Plays an upstream POP3 server for each script in turn: accepts a
+    /// connection, sends the greeting, then checks each command and answers
+    /// it.
+    async fn serve_upstream(listener: tokio::net::TcpListener, scripts: &[&[(&str, &str)]]) {
+        for script in scripts {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            stream.write_all(b"+OK upstream ready\r\n").await.unwrap();
+            for (expected, response) in *script {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                assert_eq!(line, format!("{}\r\n", expected));
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_proxy_checks_password_before_in_use() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut ctx = context();
+        ctx.proxy = Some(proxy::ProxyConfig { tls_config: None });
+        let route = format!("pop3://{}", listener.local_addr().unwrap());
+        ctx.auth_store.set_route("bob", &route).unwrap();
+        let lock = ctx
+            .session_manager
+            .try_lock_mailbox("bob", Arc::clone(&ctx.session_manager))
+            .unwrap();
+
+        let wrong_password: &[(&str, &str)] = &[
+            ("USER bob", "+OK\r\n"),
+            ("PASS wrong", "-ERR [AUTH] Invalid password\r\n"),
+        ];
+        let login: &[(&str, &str)] = &[("USER bob", "+OK\r\n"), ("PASS secret", "+OK\r\n")];
+        let relayed: &[(&str, &str)] = &[
+            ("USER bob", "+OK\r\n"),
+            ("PASS secret", "+OK\r\n"),
+            ("STAT", "+OK 1 120\r\n"),
+            ("QUIT", "+OK Bye\r\n"),
+        ];
+        let scripts = [wrong_password, login, relayed];
+        let upstream = serve_upstream(listener, &scripts);
+
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let (result, (), ()) =
+            tokio::join!(process(server, &ctx, session, None), upstream, async {
+                let mut client = BufReader::new(client);
+                let mut login = async |user: &str, password: &str| {
+                    let commands = format!("USER {}\r\nPASS {}\r\n", user, password);
+                    client.write_all(commands.as_bytes()).await.unwrap();
+                    assert!(read_response(&mut client).await.starts_with("+OK"));
+                    read_response(&mut client).await
+                };
+                let line = login("carol", "secret").await;
+                assert!(
+                    line.starts_with("-ERR [AUTH]"),
+                    "unrouted user got {:?}",
+                    line
+                );
+                let line = login("bob", "wrong").await;
+                assert!(
+                    line.starts_with("-ERR [AUTH]"),
+                    "wrong password got {:?}",
+                    line
+                );
+                let line = login("bob", "secret").await;
+                assert!(
+                    line.starts_with("-ERR [IN-USE]"),
+                    "right password got {:?}",
+                    line
+                );
+
+                drop(lock);
+                let line = login("bob", "secret").await;
+                assert!(line.starts_with("+OK"), "unexpected response {:?}", line);
+                client.write_all(b"STAT\r\n").await.unwrap();
+                assert_eq!(read_response(&mut client).await, "+OK 1 120\r\n");
+                client.write_all(b"QUIT\r\n").await.unwrap();
+                assert_eq!(read_response(&mut client).await, "+OK Bye\r\n");
+                assert_eq!(read_response(&mut client).await, "", "connection closed");
+            });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_stls_discards_pipelined_plaintext() {
+        let ctx = context();
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), true, false);
+        let (result, mut client) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            // RFC 2595 section 3.1: commands sent before the handshake must
+            // not be carried over into the TLS session.
+            client.write_all(b"STLS\r\nUSER evil\r\n").await.unwrap();
+            let line = read_response(&mut client).await;
+            assert!(line.starts_with("+OK"), "unexpected response {:?}", line);
+            client
+        });
+        let Ok(SessionEnd::StartTls(mut stream)) = result else {
+            panic!("STLS should end the plaintext session");
+        };
+        client.get_mut().shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty(), "plaintext after STLS was kept: {:?}", rest);
+        drop(stream);
+        assert_eq!(read_response(&mut client).await, "", "USER was answered");
+    }
+
+    #[tokio::test]
+    async fn test_failed_logins_disconnect() {
+        let ctx = context();
+        ctx.auth_store.create_user("alice", "secret").unwrap();
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            for _ in 0..MAX_FAILED_LOGINS {
+                client
+                    .write_all(b"USER alice\r\nPASS wrong\r\n")
+                    .await
+                    .unwrap();
+                assert!(read_response(&mut client).await.starts_with("+OK"));
+                let line = read_response(&mut client).await;
+                assert!(line.starts_with("-ERR [AUTH]"), "got {:?}", line);
+            }
+            let line = read_response(&mut client).await;
+            assert!(
+                line.starts_with("-ERR Too many failed logins"),
+                "got {:?}",
+                line
+            );
+            assert_eq!(read_response(&mut client).await, "", "connection closed");
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_quit_marks_retrieved_messages_seen() {
+        use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+        let _maildir = MAILDIR_BASE.lock().await;
+        let username = format!("seen-test-{}", std::process::id());
+        let mailbox = format!("Maildir/{}", username);
+        maildir::init_user_mailbox(&username).unwrap();
+        for name in ["1700000000.M1P1.host", "1700000000.M2P1.host"] {
+            std::fs::write(format!("{}/new/{}", mailbox, name), "Subject: x\n\n").unwrap();
+        }
+        // Messages with names that are not UTF-8 are not listed, and must
+        // come through QUIT untouched.
+        let odd: Vec<_> = [&b"1700000000.M3P1.\xff"[..], b"1700000000.M4P1.\xfe"]
+            .iter()
+            .map(|name| {
+                Path::new(&mailbox)
+                    .join("new")
+                    .join(OsStr::from_bytes(name))
+            })
+            .collect();
+        for path in &odd {
+            std::fs::write(path, "Subject: odd\n\n").unwrap();
+        }
+        let ctx = context();
+        ctx.auth_store.create_user(&username, "secret").unwrap();
+
+        let (client, server) = tokio::io::duplex(4096);
+        let session = Session::new(apop_timestamp(), false, false);
+        let (result, ()) = tokio::join!(process(server, &ctx, session, None), async {
+            let mut client = BufReader::new(client);
+            let commands = format!("USER {}\r\nPASS secret\r\nSTAT\r\n", username);
+            client.write_all(commands.as_bytes()).await.unwrap();
+            for _ in 0..2 {
+                assert!(read_response(&mut client).await.starts_with("+OK"));
+            }
+            assert_eq!(read_response(&mut client).await, "+OK 2 28\r\n");
+            client
+                .write_all(b"RETR 1\r\nRETR 2\r\nQUIT\r\n")
+                .await
+                .unwrap();
+            let mut ends = 0;
+            while ends < 2 {
+                if read_response(&mut client).await == ".\r\n" {
+                    ends += 1;
                 }
-                _ => StatusIndicator::Ok("Bye!".to_string()),
             }
+            assert!(read_response(&mut client).await.starts_with("+OK"));
+        });
+        assert!(matches!(result, Ok(SessionEnd::Closed)));
+
+        for name in ["1700000000.M1P1.host:2,S", "1700000000.M2P1.host:2,S"] {
+            assert!(Path::new(&format!("{}/cur/{}", mailbox, name)).exists());
+        }
+        for path in &odd {
+            assert_eq!(std::fs::read(path).unwrap(), b"Subject: odd\n\n");
         }
+        std::fs::remove_dir_all(&mailbox).unwrap();
+        let _ = std::fs::remove_dir("Maildir");
     }
 }
@@ -0,0 +1,242 @@
+//! Proxy mode: after USER and PASS, the session is handed to the upstream
+//! server the user is routed to and relayed as is.
+
+use std::{io, sync::Arc, time::Duration};
+
+use pop3_client::Client;
+use rustls::{ClientConfig, pki_types::ServerName};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::client::TlsStream;
+
+use crate::{IOResult, fetch::Security};
+
+/// How long connecting and logging in to the upstream server may take.
+pub const LOGIN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Settings for running as a proxy.
+pub struct ProxyConfig {
+    /// Trust anchors for upstream servers reached over TLS.
+    pub tls_config: Option<Arc<ClientConfig>>,
+}
+
+/// An upstream server, parsed from a route such as
+/// `pop3s://mail.example.com:995`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Upstream {
+    /// The server as `host:port`.
+    pub address: String,
+    host: String,
+    pub security: Security,
+}
+
+impl Upstream {
+    /// Parses a `pop3://`, `pop3s://` or `pop3+stls://` route. The port
+    /// defaults to 110, or 995 for `pop3s`.
+    pub fn parse(route: &str) -> Option<Upstream> {
+        let (scheme, authority) = route.split_once("://")?;
+        let (security, default_port) = match scheme {
+            "pop3" => (Security::Plain, 110),
+            "pop3s" => (Security::Tls, 995),
+            "pop3+stls" => (Security::Stls, 110),
+            _ => return None,
+        };
+        let authority = authority.trim_end_matches('/');
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+            _ => (authority, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return None;
+        }
+        let address = if host.contains(':') {
+            format!("[{}]:{}", host, port)
+        } else {
+            format!("{}:{}", host, port)
+        };
+        Some(Upstream {
+            address,
+            host: host.to_string(),
+            security,
+        })
+    }
+}
+
+impl std::fmt::Display for Upstream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = match self.security {
+            Security::Plain => "pop3",
+            Security::Tls => "pop3s",
+            Security::Stls => "pop3+stls",
+        };
+        write!(f, "{}://{}", scheme, self.address)
+    }
+}
+
+/// An upstream session that has been logged in to, ready to be relayed.
+pub enum Connection {
+    Plain(BufReader<TcpStream>),
+    Tls(Box<BufReader<TlsStream<TcpStream>>>),
+}
+
+/// Connects to the upstream server and logs in with USER and PASS, failing
+/// with `io::ErrorKind::TimedOut` after `LOGIN_TIMEOUT`.
+pub async fn login(
+    upstream: &Upstream,
+    config: &ProxyConfig,
+    username: &str,
+    password: &str,
+) -> pop3_client::Result<Connection> {
+    let login = connect_and_login(upstream, config, username, password);
+    match tokio::time::timeout(LOGIN_TIMEOUT, login).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+    }
+}
+
+async fn connect_and_login(
+    upstream: &Upstream,
+    config: &ProxyConfig,
+    username: &str,
+    password: &str,
+) -> pop3_client::Result<Connection> {
+    match upstream.security {
+        Security::Plain => {
+            let mut client = Client::connect(&upstream.address).await?;
+            client.login(username, password).await?;
+            Ok(Connection::Plain(client.into_inner()))
+        }
+        Security::Tls => {
+            let (server_name, tls_config) = tls_params(upstream, config)?;
+            let mut client =
+                Client::connect_tls(&upstream.address, server_name, tls_config).await?;
+            client.login(username, password).await?;
+            Ok(Connection::Tls(Box::new(client.into_inner())))
+        }
+        Security::Stls => {
+            let (server_name, tls_config) = tls_params(upstream, config)?;
+            let client = Client::connect(&upstream.address).await?;
+            let mut client = client.stls(server_name, tls_config).await?;
+            client.login(username, password).await?;
+            Ok(Connection::Tls(Box::new(client.into_inner())))
+        }
+    }
+}
+
+fn tls_params(
+    upstream: &Upstream,
+    config: &ProxyConfig,
+) -> IOResult<(ServerName<'static>, Arc<ClientConfig>)> {
+    let tls_config = config
+        .tls_config
+        .as_ref()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no CA certificates loaded"))?;
+    let server_name = ServerName::try_from(upstream.host.clone())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    Ok((server_name, Arc::clone(tls_config)))
+}
+
+/// Copies data both ways until either side closes the connection. If
+/// neither side sends anything for `idle`, the relay fails with
+/// `io::ErrorKind::TimedOut`.
+pub async fn relay<C>(client: &mut C, upstream: Connection, idle: Duration) -> IOResult<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+{
+    match upstream {
+        Connection::Plain(mut upstream) => relay_streams(client, &mut upstream, idle).await,
+        Connection::Tls(mut upstream) => relay_streams(client, upstream.as_mut(), idle).await,
+    }
+}
+
+async fn relay_streams<A, B>(a: &mut A, b: &mut B, idle: Duration) -> IOResult<()>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut a_read, mut a_write) = tokio::io::split(a);
+    let (mut b_read, mut b_write) = tokio::io::split(b);
+    let mut a_buf = vec![0u8; 8192];
+    let mut b_buf = vec![0u8; 8192];
+    loop {
+        // Reads are cancel safe, so the side that did not win loses nothing.
+        // The timeout also covers forwarding, so a peer that stops reading
+        // cannot hold the relay open either.
+        let copy = async {
+            tokio::select! {
+                read = a_read.read(&mut a_buf) => forward(&a_buf[..read?], &mut b_write).await,
+                read = b_read.read(&mut b_buf) => forward(&b_buf[..read?], &mut a_write).await,
+            }
+        };
+        match tokio::time::timeout(idle, copy).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+        }
+    }
+}
+
+/// Writes what one side sent to the other. Returns `false` once the sending
+/// side has closed the connection.
+async fn forward<W>(data: &[u8], to: &mut W) -> IOResult<bool>
+where
+    W: AsyncWrite + Unpin,
+{
+    if data.is_empty() {
+        return Ok(false);
+    }
+    to.write_all(data).await?;
+    to.flush().await?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_upstream() {
+        let upstream = Upstream::parse("pop3s://mail.example.com").unwrap();
+        assert_eq!(upstream.address, "mail.example.com:995");
+        assert_eq!(upstream.host, "mail.example.com");
+        assert_eq!(upstream.security, Security::Tls);
+
+        let upstream = Upstream::parse("pop3+stls://10.0.0.2:1110/").unwrap();
+        assert_eq!(upstream.address, "10.0.0.2:1110");
+        assert_eq!(upstream.security, Security::Stls);
+        assert_eq!(upstream.to_string(), "pop3+stls://10.0.0.2:1110");
+
+        let upstream = Upstream::parse("pop3://[::1]").unwrap();
+        assert_eq!(upstream.address, "[::1]:110");
+        assert_eq!(upstream.host, "::1");
+
+        assert_eq!(Upstream::parse("imap://mail.example.com"), None);
+        assert_eq!(Upstream::parse("pop3://mail.example.com:pop"), None);
+        assert_eq!(Upstream::parse("pop3s://"), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_relay_idle_timeout() {
+        let (mut client, mut client_end) = tokio::io::duplex(4096);
+        let (mut upstream, mut upstream_end) = tokio::io::duplex(4096);
+        let idle = Duration::from_secs(600);
+        let relay = relay_streams(&mut client_end, &mut upstream, idle);
+        let start = tokio::time::Instant::now();
+        let (result, ()) = tokio::join!(relay, async {
+            client.write_all(b"STAT\r\n").await.unwrap();
+            let mut buf = [0u8; 6];
+            upstream_end.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"STAT\r\n");
+            upstream_end.write_all(b"+OK 0 0\r\n").await.unwrap();
+            let mut buf = [0u8; 9];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"+OK 0 0\r\n");
+        });
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(start.elapsed() >= idle);
+    }
+}
@@ -0,0 +1,30 @@
+use super::{Mechanism, SaslContext, Step, verify_password};
+
+/// The non-standard but widely deployed LOGIN mechanism, which prompts for
+/// the username and password in two separate challenges.
+#[derive(Default)]
+pub struct Login {
+    username: Option<String>,
+}
+
+impl Mechanism for Login {
+    fn start(&mut self, initial_response: Option<&[u8]>, ctx: &SaslContext) -> Step {
+        match initial_response {
+            Some(response) => self.step(response, ctx),
+            None => Step::Challenge(b"Username:".to_vec()),
+        }
+    }
+
+    fn step(&mut self, response: &[u8], ctx: &SaslContext) -> Step {
+        let Ok(response) = std::str::from_utf8(response) else {
+            return Step::Failure("Invalid LOGIN response".to_string());
+        };
+        match &self.username {
+            None => {
+                self.username = Some(response.to_string());
+                Step::Challenge(b"Password:".to_vec())
+            }
+            Some(username) => verify_password(username, response, ctx),
+        }
+    }
+}
@@ -0,0 +1,480 @@
+mod login;
+mod oauth;
+mod plain;
+mod scram;
+
+pub use login::Login;
+pub use oauth::{Flavor, OAuth, OAuthConfig};
+pub use plain::Plain;
+pub use scram::Scram;
+
+use std::sync::Arc;
+
+use auth::AuthStore;
+
+/// What the server should do after feeding a client response to a mechanism.
+pub enum Step {
+    /// Send the challenge as a `+ ` continuation and wait for the next
+    /// client response.
+    Challenge(Vec<u8>),
+    /// The client authenticated as the given user.
+    Success(String),
+    /// Authentication failed; the message is returned with `-ERR`.
+    Failure(String),
+    /// The credentials could not be checked, for example because the
+    /// database failed. The client may retry later.
+    TempFailure(String),
+}
+
+/// Connection details a mechanism may need while authenticating.
+pub struct SaslContext<'a> {
+    pub auth_store: &'a AuthStore,
+    pub tls_active: bool,
+    /// The RFC 9266 `tls-exporter` channel binding data when running over
+    /// TLS.
+    pub channel_binding: Option<&'a [u8]>,
+}
+
+/// A SASL mechanism driving one authentication exchange. A fresh instance is
+/// created for every AUTH command.
+pub trait Mechanism: Send {
+    /// Starts the exchange with the client's initial response, if it sent
+    /// one on the AUTH line.
+    fn start(&mut self, initial_response: Option<&[u8]>, ctx: &SaslContext) -> Step;
+
+    /// Handles a decoded client response to the previous challenge.
+    fn step(&mut self, response: &[u8], ctx: &SaslContext) -> Step;
+}
+
+type MechanismFactory = Box<dyn Fn() -> Box<dyn Mechanism> + Send + Sync>;
+
+struct Registration {
+    name: String,
+    tls_only: bool,
+    factory: MechanismFactory,
+}
+
+/// The SASL mechanisms offered by the server, in order of registration.
+#[derive(Default)]
+pub struct SaslRegistry {
+    mechanisms: Vec<Registration>,
+}
+
+impl SaslRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn Mechanism> + Send + Sync + 'static,
+    {
+        self.insert(name, false, Box::new(factory));
+    }
+
+    /// Registers a mechanism that is only offered over TLS, such as the
+    /// channel-binding `-PLUS` variants.
+    pub fn register_tls_only<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn Mechanism> + Send + Sync + 'static,
+    {
+        self.insert(name, true, Box::new(factory));
+    }
+
+    fn insert(&mut self, name: &str, tls_only: bool, factory: MechanismFactory) {
+        self.mechanisms.retain(|m| m.name != name);
+        self.mechanisms.push(Registration {
+            name: name.to_string(),
+            tls_only,
+            factory,
+        });
+    }
+
+    /// Creates a new instance of the named mechanism, matched
+    /// case-insensitively.
+    pub fn create(&self, name: &str, tls_active: bool) -> Option<Box<dyn Mechanism>> {
+        self.mechanisms
+            .iter()
+            .filter(|m| tls_active || !m.tls_only)
+            .find(|m| m.name.eq_ignore_ascii_case(name))
+            .map(|m| (m.factory)())
+    }
+
+    pub fn names(&self, tls_active: bool) -> Vec<&str> {
+        self.mechanisms
+            .iter()
+            .filter(|m| tls_active || !m.tls_only)
+            .map(|m| m.name.as_str())
+            .collect()
+    }
+}
+
+/// Builds the registry of password-backed mechanisms.
+pub fn default_registry() -> SaslRegistry {
+    let mut registry = SaslRegistry::new();
+    registry.register("PLAIN", || Box::new(Plain));
+    registry.register("LOGIN", || Box::new(Login::default()));
+    registry.register("SCRAM-SHA-256", || Box::new(Scram::new(false)));
+    registry.register_tls_only("SCRAM-SHA-256-PLUS", || Box::new(Scram::new(true)));
+    registry
+}
+
+/// Registers the OAUTHBEARER and XOAUTH2 bearer token mechanisms. Bearer
+/// tokens are sent in the clear, so both are only offered over TLS.
+pub fn register_oauth(registry: &mut SaslRegistry, config: Arc<OAuthConfig>) {
+    let oauthbearer = Arc::clone(&config);
+    registry.register_tls_only("OAUTHBEARER", move || {
+        Box::new(OAuth::new(Arc::clone(&oauthbearer), Flavor::OAuthBearer))
+    });
+    registry.register_tls_only("XOAUTH2", move || {
+        Box::new(OAuth::new(Arc::clone(&config), Flavor::XOAuth2))
+    });
+}
+
+/// Checks a username and password against the `AuthStore`.
+fn verify_password(username: &str, password: &str, ctx: &SaslContext) -> Step {
+    match ctx.auth_store.login(username, password) {
+        Ok(true) => Step::Success(username.to_string()),
+        Ok(false) => Step::Failure("Username or password are incorrect".to_string()),
+        Err(e) => {
+            println!("{}", e);
+            Step::TempFailure("Unable to verify credentials".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth_store() -> AuthStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let auth_store = AuthStore::new(db);
+        auth_store.create_user("tim", "tanstaaftanstaaf").unwrap();
+        auth_store
+    }
+
+    #[test]
+    fn test_plain() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: false,
+            channel_binding: None,
+        };
+        let registry = default_registry();
+
+        let mut plain = registry.create("plain", false).unwrap();
+        assert!(
+            matches!(plain.start(None, &ctx), Step::Challenge(c) if c.is_empty()),
+            "PLAIN without an initial response should send an empty challenge"
+        );
+        assert!(matches!(
+            plain.step(b"\0tim\0tanstaaftanstaaf", &ctx),
+            Step::Success(user) if user == "tim"
+        ));
+
+        let mut plain = registry.create("PLAIN", false).unwrap();
+        assert!(matches!(
+            plain.start(Some(b"\0tim\0wrong"), &ctx),
+            Step::Failure(_)
+        ));
+
+        let mut plain = registry.create("PLAIN", false).unwrap();
+        assert!(
+            matches!(
+                plain.start(Some(b"admin\0tim\0tanstaaftanstaaf"), &ctx),
+                Step::Failure(_)
+            ),
+            "authorizing as another user should fail"
+        );
+    }
+
+    #[test]
+    fn test_login() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: false,
+            channel_binding: None,
+        };
+        let registry = default_registry();
+
+        let mut login = registry.create("LOGIN", false).unwrap();
+        assert!(matches!(login.start(None, &ctx), Step::Challenge(c) if c == b"Username:"));
+        assert!(matches!(login.step(b"tim", &ctx), Step::Challenge(c) if c == b"Password:"));
+        assert!(matches!(
+            login.step(b"tanstaaftanstaaf", &ctx),
+            Step::Success(user) if user == "tim"
+        ));
+
+        let mut login = registry.create("LOGIN", false).unwrap();
+        assert!(
+            matches!(login.start(Some(b"tim"), &ctx), Step::Challenge(c) if c == b"Password:"),
+            "an initial response should be taken as the username"
+        );
+        assert!(matches!(login.step(b"wrong", &ctx), Step::Failure(_)));
+    }
+
+    /// Plays the client side of a SCRAM-SHA-256 exchange.
+    fn scram_exchange(
+        mechanism: &mut Box<dyn Mechanism>,
+        ctx: &SaslContext,
+        gs2_header: &str,
+        cbind_data: &[u8],
+        password: &str,
+    ) -> Step {
+        use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+        use hmac::{Hmac, Mac};
+        use sha2::{Digest, Sha256};
+
+        let client_first_bare = "n=tim,r=fyko+d2lbbFgONRv9qkxdawL";
+        let client_first = format!("{}{}", gs2_header, client_first_bare);
+        let Step::Challenge(server_first) = mechanism.start(Some(client_first.as_bytes()), ctx)
+        else {
+            panic!("expected server-first challenge");
+        };
+        let server_first = String::from_utf8(server_first).unwrap();
+        let mut nonce = "";
+        let mut salt = Vec::new();
+        let mut iterations = 0;
+        for attr in server_first.split(',') {
+            match attr.split_once('=').unwrap() {
+                ("r", value) => nonce = value,
+                ("s", value) => salt = BASE64.decode(value).unwrap(),
+                ("i", value) => iterations = value.parse().unwrap(),
+                _ => {}
+            }
+        }
+
+        let mut channel_binding = gs2_header.as_bytes().to_vec();
+        channel_binding.extend_from_slice(cbind_data);
+        let without_proof = format!("c={},r={}", BASE64.encode(channel_binding), nonce);
+        let auth_message = format!("{},{},{}", client_first_bare, server_first, without_proof);
+
+        let hmac = |key: &[u8], data: &[u8]| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        };
+        let mut salted_password = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(password.as_bytes(), &salt, iterations, &mut salted_password);
+        let client_key = hmac(&salted_password, b"Client Key");
+        let stored_key = Sha256::digest(&client_key);
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        let proof: Vec<u8> = client_key
+            .iter()
+            .zip(client_signature)
+            .map(|(k, s)| k ^ s)
+            .collect();
+        let client_final = format!("{},p={}", without_proof, BASE64.encode(proof));
+
+        match mechanism.step(client_final.as_bytes(), ctx) {
+            Step::Challenge(server_final) => {
+                let server_key = hmac(&salted_password, b"Server Key");
+                let expected = hmac(&server_key, auth_message.as_bytes());
+                assert_eq!(
+                    server_final,
+                    format!("v={}", BASE64.encode(expected)).into_bytes(),
+                    "server signature should verify"
+                );
+                mechanism.step(b"", ctx)
+            }
+            step => step,
+        }
+    }
+
+    #[test]
+    fn test_scram_sha_256() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: false,
+            channel_binding: None,
+        };
+        let registry = default_registry();
+        assert!(registry.create("SCRAM-SHA-256-PLUS", false).is_none());
+
+        let mut scram = registry.create("SCRAM-SHA-256", false).unwrap();
+        let step = scram_exchange(&mut scram, &ctx, "n,,", b"", "tanstaaftanstaaf");
+        assert!(matches!(step, Step::Success(user) if user == "tim"));
+
+        let mut scram = registry.create("SCRAM-SHA-256", false).unwrap();
+        let step = scram_exchange(&mut scram, &ctx, "n,,", b"", "wrong");
+        assert!(matches!(step, Step::Failure(_)));
+
+        // An unknown user is sent the same salt on every attempt and only
+        // fails at client-final, like a wrong password.
+        let mut salts = Vec::new();
+        for _ in 0..2 {
+            let mut scram = registry.create("SCRAM-SHA-256", false).unwrap();
+            let Step::Challenge(server_first) = scram.start(Some(b"n,,n=nobody,r=abc"), &ctx)
+            else {
+                panic!("expected server-first challenge for an unknown user");
+            };
+            let server_first = String::from_utf8(server_first).unwrap();
+            let (nonce, salt) = server_first.split_once(',').unwrap();
+            assert!(salt.ends_with(",i=4096"));
+            salts.push(salt.to_string());
+            let client_final = format!("c=biws,{},p={}", nonce, "A".repeat(43) + "=");
+            assert!(matches!(
+                scram.step(client_final.as_bytes(), &ctx),
+                Step::Failure(_)
+            ));
+        }
+        assert_eq!(salts[0], salts[1]);
+    }
+
+    #[test]
+    fn test_scram_sha_256_plus() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: true,
+            channel_binding: Some(b"exported keying material"),
+        };
+        let registry = default_registry();
+
+        let mut scram = registry.create("SCRAM-SHA-256-PLUS", true).unwrap();
+        let step = scram_exchange(
+            &mut scram,
+            &ctx,
+            "p=tls-exporter,,",
+            b"exported keying material",
+            "tanstaaftanstaaf",
+        );
+        assert!(matches!(step, Step::Success(user) if user == "tim"));
+
+        let mut scram = registry.create("SCRAM-SHA-256-PLUS", true).unwrap();
+        let step = scram_exchange(
+            &mut scram,
+            &ctx,
+            "p=tls-exporter,,",
+            b"another connection",
+            "tanstaaftanstaaf",
+        );
+        assert!(
+            matches!(step, Step::Failure(_)),
+            "binding data from another connection should be rejected"
+        );
+
+        let mut scram = registry.create("SCRAM-SHA-256", true).unwrap();
+        assert!(
+            matches!(scram.start(Some(b"y,,n=tim,r=abc"), &ctx), Step::Failure(_)),
+            "'y' while -PLUS is offered indicates a downgrade"
+        );
+    }
+
+    fn oauth_registry() -> SaslRegistry {
+        let jwks = serde_json::json!({
+            "keys": [{
+                "kty": "oct",
+                "kid": "test-key",
+                "alg": "HS256",
+                "k": "c2VjcmV0LXNpZ25pbmcta2V5LWZvci10ZXN0cw",
+            }]
+        });
+        let config = OAuthConfig {
+            jwks: serde_json::from_value(jwks).unwrap(),
+            issuer: "https://sso.example.com".to_string(),
+            audience: "pop3".to_string(),
+            username_claim: "sub".to_string(),
+        };
+        let mut registry = SaslRegistry::new();
+        register_oauth(&mut registry, Arc::new(config));
+        registry
+    }
+
+    fn mint_token(sub: &str, aud: &str, expires_in: i64) -> String {
+        use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as i64;
+        let claims = serde_json::json!({
+            "iss": "https://sso.example.com",
+            "aud": aud,
+            "sub": sub,
+            "exp": now + expires_in,
+        });
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("test-key".to_string());
+        jsonwebtoken::encode(
+            &header,
+            &claims,
+            &EncodingKey::from_secret(b"secret-signing-key-for-tests"),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_oauthbearer() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: true,
+            channel_binding: None,
+        };
+        let registry = oauth_registry();
+        let message = |token: &str| {
+            format!(
+                "n,a=tim,\x01host=mail.example.com\x01port=995\x01auth=Bearer {}\x01\x01",
+                token
+            )
+        };
+
+        let mut oauth = registry.create("OAUTHBEARER", true).unwrap();
+        let step = oauth.start(
+            Some(message(&mint_token("tim", "pop3", 300)).as_bytes()),
+            &ctx,
+        );
+        assert!(matches!(step, Step::Success(user) if user == "tim"));
+
+        for (token, reason) in [
+            (mint_token("tim", "pop3", -300), "expired"),
+            (mint_token("tim", "imap", 300), "wrong audience"),
+            (mint_token("nobody", "pop3", 300), "another user"),
+        ] {
+            let mut oauth = registry.create("OAUTHBEARER", true).unwrap();
+            let Step::Challenge(error) = oauth.start(Some(message(&token).as_bytes()), &ctx) else {
+                panic!(
+                    "{} token should be answered with an error challenge",
+                    reason
+                );
+            };
+            let error: serde_json::Value = serde_json::from_slice(&error).unwrap();
+            assert_eq!(error["status"], "invalid_token");
+            assert!(
+                matches!(oauth.step(b"\x01", &ctx), Step::Failure(_)),
+                "{} token should fail once the client acknowledges the error",
+                reason
+            );
+        }
+    }
+
+    #[test]
+    fn test_xoauth2() {
+        let auth_store = auth_store();
+        let ctx = SaslContext {
+            auth_store: &auth_store,
+            tls_active: true,
+            channel_binding: None,
+        };
+        let registry = oauth_registry();
+        let token = mint_token("tim", "pop3", 300);
+
+        let mut oauth = registry.create("XOAUTH2", true).unwrap();
+        let message = format!("user=tim\x01auth=Bearer {}\x01\x01", token);
+        let step = oauth.start(Some(message.as_bytes()), &ctx);
+        assert!(matches!(step, Step::Success(user) if user == "tim"));
+
+        let mut oauth = registry.create("XOAUTH2", true).unwrap();
+        let message = format!("user=admin\x01auth=Bearer {}\x01\x01", token);
+        let step = oauth.start(Some(message.as_bytes()), &ctx);
+        assert!(
+            matches!(step, Step::Challenge(_)),
+            "a token for another user should be rejected"
+        );
+    }
+}
@@ -0,0 +1,177 @@
+use std::{io, path::Path, sync::Arc};
+
+use jsonwebtoken::{Algorithm, DecodingKey, Validation, jwk::JwkSet};
+use serde_json::Value;
+
+use super::{Mechanism, SaslContext, Step};
+
+/// How bearer tokens are checked. Keys come from a JWKS file so no network
+/// access is needed at login time.
+pub struct OAuthConfig {
+    pub jwks: JwkSet,
+    pub issuer: String,
+    pub audience: String,
+    /// The claim holding the `AuthStore` username, usually `sub` or `email`.
+    pub username_claim: String,
+}
+
+impl OAuthConfig {
+    pub fn load(
+        jwks_path: &Path,
+        issuer: &str,
+        audience: &str,
+        username_claim: &str,
+    ) -> io::Result<Self> {
+        let jwks = std::fs::read(jwks_path)?;
+        let jwks = serde_json::from_slice(&jwks).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", jwks_path.display(), e),
+            )
+        })?;
+        Ok(Self {
+            jwks,
+            issuer: issuer.to_string(),
+            audience: audience.to_string(),
+            username_claim: username_claim.to_string(),
+        })
+    }
+
+    /// Verifies the token's signature and claims, returning the username it
+    /// was issued to.
+    fn verify(&self, token: &str) -> Result<String, String> {
+        let header = jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let jwk = match &header.kid {
+            Some(kid) => self.jwks.find(kid),
+            None if self.jwks.keys.len() == 1 => self.jwks.keys.first(),
+            None => None,
+        }
+        .ok_or("no matching key")?;
+        if let Some(alg) = jwk.common.key_algorithm
+            && alg.to_string().parse::<Algorithm>().ok() != Some(header.alg)
+        {
+            return Err("algorithm does not match key".to_string());
+        }
+        let key = DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+        let claims = jsonwebtoken::decode::<Value>(token, &key, &validation)
+            .map_err(|e| e.to_string())?
+            .claims;
+        claims
+            .get(&self.username_claim)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| format!("missing {} claim", self.username_claim))
+    }
+}
+
+/// Which client message format is in use.
+#[derive(Clone, Copy)]
+pub enum Flavor {
+    /// RFC 7628 OAUTHBEARER.
+    OAuthBearer,
+    /// Google's XOAUTH2, still used by many clients.
+    XOAuth2,
+}
+
+pub struct OAuth {
+    config: Arc<OAuthConfig>,
+    flavor: Flavor,
+    failed: bool,
+}
+
+impl OAuth {
+    pub fn new(config: Arc<OAuthConfig>, flavor: Flavor) -> Self {
+        Self {
+            config,
+            flavor,
+            failed: false,
+        }
+    }
+
+    /// Extracts the requested user (if any) and bearer token.
+    fn parse(&self, message: &str) -> Option<(Option<String>, String)> {
+        let (user, fields) = match self.flavor {
+            Flavor::OAuthBearer => {
+                let (gs2_header, fields) = message.split_once('\x01')?;
+                let mut gs2 = gs2_header.split(',');
+                if gs2.next()? != "n" {
+                    return None;
+                }
+                let user = gs2
+                    .next()
+                    .and_then(|a| a.strip_prefix("a="))
+                    .map(|a| a.replace("=2C", ",").replace("=3D", "="));
+                (user, fields)
+            }
+            Flavor::XOAuth2 => {
+                let user = message
+                    .split('\x01')
+                    .find_map(|field| field.strip_prefix("user="))
+                    .map(str::to_string);
+                (user, message)
+            }
+        };
+        let token = fields
+            .split('\x01')
+            .find_map(|field| field.strip_prefix("auth="))
+            .and_then(|auth| {
+                let (scheme, token) = auth.split_once(' ')?;
+                scheme.eq_ignore_ascii_case("bearer").then_some(token)
+            })?;
+        Some((user, token.to_string()))
+    }
+
+    /// Sends the RFC 7628 error as a challenge; the client must answer it
+    /// before the exchange fails.
+    fn error(&mut self, reason: &str) -> Step {
+        println!("bearer token rejected: {}", reason);
+        self.failed = true;
+        let error = serde_json::json!({
+            "status": "invalid_token",
+            "schemes": "bearer",
+            "scope": "pop3",
+        });
+        Step::Challenge(error.to_string().into_bytes())
+    }
+}
+
+impl Mechanism for OAuth {
+    fn start(&mut self, initial_response: Option<&[u8]>, ctx: &SaslContext) -> Step {
+        match initial_response {
+            Some(response) => self.step(response, ctx),
+            None => Step::Challenge(Vec::new()),
+        }
+    }
+
+    fn step(&mut self, response: &[u8], ctx: &SaslContext) -> Step {
+        if self.failed {
+            return Step::Failure("Authentication failed".to_string());
+        }
+        let Some((requested_user, token)) = std::str::from_utf8(response)
+            .ok()
+            .and_then(|message| self.parse(message))
+        else {
+            return Step::Failure("Invalid bearer token message".to_string());
+        };
+        let username = match self.config.verify(&token) {
+            Ok(username) => username,
+            Err(reason) => return self.error(&reason),
+        };
+        if requested_user.is_some_and(|user| user != username) {
+            return self.error("token issued to another user");
+        }
+        match ctx.auth_store.user_exists(&username) {
+            Ok(true) => Step::Success(username),
+            Ok(false) => self.error("unknown user"),
+            Err(e) => {
+                println!("{}", e);
+                Step::Failure("Authentication failed".to_string())
+            }
+        }
+    }
+}
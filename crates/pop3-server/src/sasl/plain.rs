@@ -0,0 +1,28 @@
+use super::{Mechanism, SaslContext, Step, verify_password};
+
+/// The PLAIN mechanism (RFC 4616): a single `authzid NUL authcid NUL passwd`
+/// message.
+pub struct Plain;
+
+impl Mechanism for Plain {
+    fn start(&mut self, initial_response: Option<&[u8]>, ctx: &SaslContext) -> Step {
+        match initial_response {
+            Some(response) => self.step(response, ctx),
+            None => Step::Challenge(Vec::new()),
+        }
+    }
+
+    fn step(&mut self, response: &[u8], ctx: &SaslContext) -> Step {
+        let Ok(message) = std::str::from_utf8(response) else {
+            return Step::Failure("Invalid PLAIN message".to_string());
+        };
+        let parts: Vec<&str> = message.split('\0').collect();
+        let [authzid, authcid, password] = parts[..] else {
+            return Step::Failure("Invalid PLAIN message".to_string());
+        };
+        if !authzid.is_empty() && authzid != authcid {
+            return Step::Failure("Not authorized to act as another user".to_string());
+        }
+        verify_password(authcid, password, ctx)
+    }
+}
@@ -0,0 +1,205 @@
+use auth::ScramCredentials;
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use rand::{RngCore, rngs::OsRng};
+
+use super::{Mechanism, SaslContext, Step};
+
+/// The only channel binding type rustls can provide (RFC 9266).
+const CHANNEL_BINDING_TYPE: &str = "tls-exporter";
+
+/// SCRAM-SHA-256 and SCRAM-SHA-256-PLUS (RFC 5802, RFC 7677), verified
+/// against the keys stored by `AuthStore`.
+pub struct Scram {
+    plus: bool,
+    state: ScramState,
+}
+
+enum ScramState {
+    Initial,
+    ServerFirstSent {
+        username: String,
+        credentials: ScramCredentials,
+        gs2_header: String,
+        client_first_bare: String,
+        server_first: String,
+        nonce: String,
+    },
+    ServerFinalSent {
+        username: String,
+    },
+    Done,
+}
+
+impl Scram {
+    pub fn new(plus: bool) -> Self {
+        Self {
+            plus,
+            state: ScramState::Initial,
+        }
+    }
+
+    fn client_first(&mut self, message: &str, ctx: &SaslContext) -> Step {
+        let mut parts = message.splitn(3, ',');
+        let (Some(cbind_flag), Some(authzid), Some(client_first_bare)) =
+            (parts.next(), parts.next(), parts.next())
+        else {
+            return failure("Invalid client-first message");
+        };
+
+        match (cbind_flag, self.plus) {
+            ("n", false) => {}
+            // The client supports channel binding but thinks we don't. If we
+            // advertised -PLUS, this indicates a downgrade attack.
+            ("y", false) if ctx.channel_binding.is_none() => {}
+            (flag, true) if flag == format!("p={}", CHANNEL_BINDING_TYPE) => {}
+            _ => return failure("Channel binding mismatch"),
+        }
+
+        let mut username = None;
+        let mut client_nonce = None;
+        for attr in client_first_bare.split(',') {
+            match attr.split_once('=') {
+                Some(("n", value)) => username = decode_saslname(value),
+                Some(("r", value)) => client_nonce = Some(value),
+                Some(("m", _)) => return failure("Unsupported mandatory extension"),
+                _ => {}
+            }
+        }
+        let (Some(username), Some(client_nonce)) = (username, client_nonce) else {
+            return failure("Invalid client-first message");
+        };
+        match authzid.strip_prefix("a=") {
+            None if authzid.is_empty() => {}
+            Some(authzid) if decode_saslname(authzid).as_deref() == Some(username.as_str()) => {}
+            _ => return failure("Not authorized to act as another user"),
+        }
+
+        // Unknown users get a salt too and fail at client-final, so the
+        // exchange does not reveal which accounts exist.
+        let credentials = match ctx.auth_store.scram_credentials(&username) {
+            Ok(Some(credentials)) => credentials,
+            Ok(None) => match ctx.auth_store.fake_scram_credentials(&username) {
+                Ok(credentials) => credentials,
+                Err(e) => return temp_failure(e),
+            },
+            Err(e) => return temp_failure(e),
+        };
+
+        let mut server_nonce = [0u8; 18];
+        OsRng.fill_bytes(&mut server_nonce);
+        let nonce = format!("{}{}", client_nonce, BASE64.encode(server_nonce));
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce,
+            BASE64.encode(&credentials.salt),
+            credentials.iterations
+        );
+        let challenge = server_first.clone().into_bytes();
+        self.state = ScramState::ServerFirstSent {
+            username,
+            credentials,
+            gs2_header: format!("{},{},", cbind_flag, authzid),
+            client_first_bare: client_first_bare.to_string(),
+            server_first,
+            nonce,
+        };
+        Step::Challenge(challenge)
+    }
+}
+
+impl Mechanism for Scram {
+    fn start(&mut self, initial_response: Option<&[u8]>, ctx: &SaslContext) -> Step {
+        match initial_response {
+            Some(response) => self.step(response, ctx),
+            None => Step::Challenge(Vec::new()),
+        }
+    }
+
+    fn step(&mut self, response: &[u8], ctx: &SaslContext) -> Step {
+        let Ok(message) = std::str::from_utf8(response) else {
+            return failure("Invalid SCRAM message");
+        };
+        match std::mem::replace(&mut self.state, ScramState::Done) {
+            ScramState::Initial => self.client_first(message, ctx),
+            ScramState::ServerFirstSent {
+                username,
+                credentials,
+                gs2_header,
+                client_first_bare,
+                server_first,
+                nonce,
+            } => {
+                let Some((without_proof, proof)) = message.rsplit_once(",p=") else {
+                    return failure("Invalid client-final message");
+                };
+                let mut binding = None;
+                let mut client_nonce = None;
+                for attr in without_proof.split(',') {
+                    match attr.split_once('=') {
+                        Some(("c", value)) => binding = BASE64.decode(value).ok(),
+                        Some(("r", value)) => client_nonce = Some(value),
+                        _ => {}
+                    }
+                }
+
+                let mut expected_binding = gs2_header.into_bytes();
+                if self.plus {
+                    match ctx.channel_binding {
+                        Some(data) => expected_binding.extend_from_slice(data),
+                        None => return failure("Channel binding unavailable"),
+                    }
+                }
+                if binding.as_deref() != Some(expected_binding.as_slice()) {
+                    return failure("Channel binding mismatch");
+                }
+                if client_nonce != Some(nonce.as_str()) {
+                    return failure("Nonce mismatch");
+                }
+                let Ok(proof) = BASE64.decode(proof) else {
+                    return failure("Invalid client proof");
+                };
+
+                let auth_message =
+                    format!("{},{},{}", client_first_bare, server_first, without_proof);
+                if !credentials.verify_client_proof(auth_message.as_bytes(), &proof) {
+                    return failure("Authentication failed");
+                }
+                let signature = credentials.server_signature(auth_message.as_bytes());
+                self.state = ScramState::ServerFinalSent { username };
+                Step::Challenge(format!("v={}", BASE64.encode(signature)).into_bytes())
+            }
+            // The client acknowledges server-final with an empty response.
+            ScramState::ServerFinalSent { username } if response.is_empty() => {
+                Step::Success(username)
+            }
+            _ => failure("Unexpected SCRAM message"),
+        }
+    }
+}
+
+fn failure(msg: &str) -> Step {
+    Step::Failure(msg.to_string())
+}
+
+/// Logs a credential store error and fails without blaming the client.
+fn temp_failure(e: sled::Error) -> Step {
+    println!("{}", e);
+    Step::TempFailure("Unable to verify credentials".to_string())
+}
+
+/// Decodes a SCRAM saslname, where "," and "=" are escaped as "=2C" and "=3D".
+fn decode_saslname(value: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = value;
+    while let Some(i) = rest.find('=') {
+        decoded.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 3) {
+            Some("2C") => decoded.push(','),
+            Some("3D") => decoded.push('='),
+            _ => return None,
+        }
+        rest = &rest[i + 3..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
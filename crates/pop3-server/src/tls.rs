@@ -0,0 +1,67 @@
+use std::{io, path::Path, sync::Arc};
+
+use rustls::{
+    ClientConfig, RootCertStore, ServerConfig,
+    pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+
+/// Where the system CA bundle usually lives.
+pub const DEFAULT_CA_BUNDLE: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Builds a TLS acceptor from a PEM certificate chain and PEM private key.
+pub fn load_acceptor(cert_path: &Path, key_path: &Path) -> io::Result<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid_data(key_path, e))?;
+
+    let mut config =
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .map_err(io::Error::other)?;
+    // The tls-exporter channel binding is only secure on TLS 1.2 with the
+    // extended master secret (RFC 9266 section 3).
+    config.require_ems = true;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Builds a client configuration that trusts the CA certificates in a PEM
+/// bundle, for connecting to remote servers.
+pub fn load_client_config(ca_path: &Path) -> io::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    let certs = CertificateDer::pem_file_iter(ca_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_data(ca_path, e))?;
+    let (added, _ignored) = roots.add_parsable_certificates(certs);
+    if added == 0 {
+        return Err(invalid_data(ca_path, "no usable CA certificates"));
+    }
+
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Exports the RFC 9266 `tls-exporter` channel binding data for an
+/// established connection.
+pub fn channel_binding<IO>(stream: &TlsStream<IO>) -> Option<Vec<u8>> {
+    let (_, connection) = stream.get_ref();
+    connection
+        .export_keying_material(vec![0u8; 32], b"EXPORTER-Channel-Binding", None)
+        .ok()
+}
+
+fn invalid_data(path: &Path, e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {}", path.display(), e),
+    )
+}